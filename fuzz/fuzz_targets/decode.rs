@@ -0,0 +1,75 @@
+//! Full decode through every entry point, with the default options. None may
+//! panic, and whenever two of them accept a stream they must agree on its
+//! pixels.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qoi_decode::{
+    decode_into, decode_to_vec_recovering, decode_to_vec_with_options, ChannelOrder, Channels,
+    DecodeOptions, Layout, PixelRGBA, QOIImage, StreamDecoder,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let lenient = DecodeOptions::default();
+    let strict = DecodeOptions {
+        strict: true,
+        ..DecodeOptions::default()
+    };
+
+    let decoded = decode_to_vec_with_options(data, &lenient);
+    let strict_decoded = decode_to_vec_with_options(data, &strict);
+    if let Ok(strict_decoded) = &strict_decoded {
+        assert!(decoded.as_ref().is_ok_and(|d| d == strict_decoded));
+    }
+
+    let image = QOIImage::from_reader_with_options(data, &lenient);
+    assert_eq!(image.is_ok(), decoded.is_ok());
+    if let Ok(image) = &image {
+        // a lenient image never falls short of its header
+        let mat = image.to_rgba_mat();
+        let rows = if image.width() == 0 {
+            0
+        } else {
+            image.height()
+        };
+        assert_eq!(mat.len(), rows as usize);
+        assert_eq!(image.chunks().count(), image.chunk_count());
+        let _ = qoi_decode::asm::disassemble(image);
+        let channels = image.channels().count();
+        let packed: Vec<u8> = mat
+            .iter()
+            .flatten()
+            .flat_map(|&px| <[u8; 4]>::from(px)[..channels].to_vec())
+            .collect();
+        assert!(decoded.as_ref().is_ok_and(|(_, pixels)| *pixels == packed));
+    }
+    let strict_image = QOIImage::from_reader_with_options(data, &strict);
+    assert_eq!(strict_image.is_ok(), strict_decoded.is_ok());
+    if let (Ok(image), Ok((_, pixels))) = (&strict_image, &strict_decoded) {
+        let mut buf = vec![0; pixels.len()];
+        let order = match image.channels() {
+            Channels::RGB => ChannelOrder::RGB,
+            Channels::RGBA => ChannelOrder::RGBA,
+        };
+        let layout = Layout::packed(order, image.width());
+        image.decode_into(&mut buf, layout).unwrap();
+        assert!(&buf == pixels);
+    }
+
+    let streamed = StreamDecoder::new(data).and_then(|d| d.collect::<Result<Vec<_>, _>>());
+    assert_eq!(streamed.is_ok(), decoded.is_ok());
+
+    let Ok((header, pixels)) = decoded else {
+        return;
+    };
+    let layout = Layout::packed(ChannelOrder::RGBA, header.width);
+    let mut buf = vec![0; layout.required_len(header.width, header.height) as usize];
+    decode_into(data, &mut buf, layout).unwrap();
+
+    let (_, recovered, report) = decode_to_vec_recovering(data, PixelRGBA::default()).unwrap();
+    assert!(report.error.is_none());
+    assert!(recovered == pixels);
+
+    assert!(streamed.unwrap().concat() == pixels);
+});
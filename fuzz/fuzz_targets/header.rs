@@ -0,0 +1,15 @@
+//! Header parsing: anything `read_header` accepts must write back out to the
+//! same 14 bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qoi_decode::{read_header, Header};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(header) = read_header(data) else {
+        return;
+    };
+    assert_eq!(header.to_bytes(), data[..14]);
+    assert_eq!(Header::from_bytes(&header.to_bytes()).unwrap(), header);
+});
@@ -0,0 +1,50 @@
+//! Encode→decode round trip: the first two bytes pick the channel count and
+//! width, and the rest are the pixels, cut down to whole rows.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use qoi_decode::{
+    decode_to_vec_with_options, encode_to_vec, Channels, ColorSpace, DecodeOptions, Header,
+    QOIImage, StreamEncoder,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let [shape, width, pixels @ ..] = data else {
+        return;
+    };
+    let channels = if shape & 1 == 0 {
+        Channels::RGB
+    } else {
+        Channels::RGBA
+    };
+    let width = *width as u32;
+    let height = match width {
+        // zero-width images can still claim any height
+        0 => (shape >> 1) as u32,
+        _ => (pixels.len() / (width as usize * channels.count())) as u32,
+    };
+    let header = Header {
+        width,
+        height,
+        channels,
+        color_space: ColorSpace::SRGB,
+    };
+    let pixels = &pixels[..(width * height) as usize * channels.count()];
+
+    let encoded = encode_to_vec(pixels, header).unwrap();
+    let strict = DecodeOptions {
+        strict: true,
+        ..DecodeOptions::default()
+    };
+    let (decoded_header, decoded) = decode_to_vec_with_options(&encoded, &strict).unwrap();
+    assert_eq!(decoded_header, header);
+    assert!(decoded == pixels);
+
+    let image = QOIImage::from_packed(pixels, header).unwrap();
+    assert!(image.serialize() == encoded);
+
+    let mut encoder = StreamEncoder::new(Vec::new(), header).unwrap();
+    encoder.write_pixels(pixels).unwrap();
+    assert!(encoder.finish().unwrap() == encoded);
+});
@@ -0,0 +1,422 @@
+//! A text form of QOI chunk streams, for reading encoder output and for
+//! writing edge-case files by hand.
+//!
+//! A listing starts with a header line and then has one chunk per line:
+//!
+//! ```text
+//! HEADER width=5 height=1 channels=3 colorspace=srgb
+//!       14: RGB r=10 g=20 b=30              ; px 0 #0a141eff
+//!       18: LUMA dg=3 dr-dg=0 db-dg=0       ; px 1 #0d1721ff
+//!       20: RUN 2                           ; px 2-3 #0d1721ff
+//!       21: INDEX 9                         ; px 4 #0a141eff
+//! ; end marker at 22, 30 bytes in all
+//! ```
+//!
+//! The other mnemonics are `RGBA r= g= b= a=` and `DIFF dr= dg= db=`. `RUN`
+//! takes the number of pixels, 1 to 62, not the biased value stored in the
+//! file, and `colorspace` is `srgb` or `linear`. Everything after a `;` is a
+//! comment. The byte offset before each chunk is only there for reading and
+//! is ignored by [`assemble`], so lines can be added or removed freely.
+//! Mnemonics and field names may be in any case, and fields in any order.
+//!
+//! Listings are assembled chunk for chunk, without checking that they add up
+//! to the pixel count in the header, so truncated or overlong streams can be
+//! written on purpose.
+
+use std::fmt;
+use std::fmt::Write;
+
+use crate::{
+    Channels, Chunk, ColorSpace, DiffRGB, Header, Index, Luma, PixelRGB, PixelRGBA, QOIImage, Run,
+};
+
+/// An error from [`assemble`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsmError {
+    /// The listing has no `HEADER` line before its first chunk.
+    MissingHeader,
+    /// A line could not be read. Lines count from one.
+    BadLine { line: usize, reason: LineError },
+}
+
+/// Why a line of a listing could not be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineError {
+    /// The line starts with a word that is not a mnemonic.
+    UnknownMnemonic,
+    /// The listing has a second `HEADER` line.
+    SecondHeader,
+    /// `colorspace` is neither `srgb` nor `linear`.
+    BadColorSpace,
+    /// `INDEX` or `RUN` has no operand.
+    MissingOperand,
+    /// `INDEX` or `RUN` has more than one operand.
+    TooManyOperands,
+    /// A value is not a whole number.
+    NotANumber,
+    /// The `RUN` length is not between 1 and 62.
+    RunOutOfRange,
+    /// The `INDEX` is not between 0 and 63.
+    IndexOutOfRange,
+    /// A field's value does not fit the field.
+    ValueOutOfRange,
+    /// An argument is not of the form `name=value`.
+    NotAField,
+    /// A field the line needs is not given.
+    MissingField,
+    /// A field is given more than once.
+    RepeatedField,
+    /// A field the line does not take is given.
+    UnknownField,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LineError::UnknownMnemonic => "unknown mnemonic",
+            LineError::SecondHeader => "second HEADER line",
+            LineError::BadColorSpace => "colorspace must be srgb or linear",
+            LineError::MissingOperand => "missing operand",
+            LineError::TooManyOperands => "too many operands",
+            LineError::NotANumber => "not a number",
+            LineError::RunOutOfRange => "run must be 1 to 62 pixels",
+            LineError::IndexOutOfRange => "index must be 0 to 63",
+            LineError::ValueOutOfRange => "value out of range",
+            LineError::NotAField => "expected name=value",
+            LineError::MissingField => "missing field",
+            LineError::RepeatedField => "field given more than once",
+            LineError::UnknownField => "unknown field",
+        })
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::MissingHeader => write!(f, "Malformed QOI listing: no HEADER line"),
+            AsmError::BadLine { line, reason } => {
+                write!(f, "Malformed QOI listing: line {line}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Writes out an image's chunks as a listing that [`assemble`] turns back into
+/// the same image.
+pub fn disassemble(image: &QOIImage) -> String {
+    let header = image.header();
+    let mut out = String::new();
+    let channels = header.channels.count();
+    let color_space = match header.color_space {
+        ColorSpace::SRGB => "srgb",
+        ColorSpace::Linear => "linear",
+    };
+    // writing to a String cannot fail
+    let _ = writeln!(
+        out,
+        "HEADER width={} height={} channels={channels} colorspace={color_space}",
+        header.width, header.height
+    );
+
+    let mut end = 14;
+    for info in image.chunks() {
+        let instruction = match &info.chunk {
+            Chunk::RGB(px) => format!("RGB r={} g={} b={}", px.r, px.g, px.b),
+            Chunk::RGBA(px) => format!("RGBA r={} g={} b={} a={}", px.r, px.g, px.b, px.a),
+            Chunk::Index(i) => format!("INDEX {}", i.position()),
+            Chunk::Diff(d) => format!("DIFF dr={} dg={} db={}", d.dr(), d.dg(), d.db()),
+            Chunk::Luma(l) => format!("LUMA dg={} dr-dg={} db-dg={}", l.dg(), l.dr_dg(), l.db_dg()),
+            Chunk::Run(run) => format!("RUN {}", run.pixel_count()),
+        };
+        let pixels = match info.pixel_count {
+            0 => "past the end".to_string(),
+            1 => info.pixel_index.to_string(),
+            n => format!("{}-{}", info.pixel_index, info.pixel_index + n - 1),
+        };
+        let _ = writeln!(
+            out,
+            "{:>8}: {instruction:<32}; px {pixels} #{:08x}",
+            info.offset,
+            u32::from(info.pixel)
+        );
+        end = info.offset + info.chunk.encoded_len();
+    }
+    let _ = writeln!(out, "; end marker at {end}, {} bytes in all", end + 8);
+    out
+}
+
+/// Reads a listing written by [`disassemble`] or by hand. Call
+/// [`QOIImage::serialize`] on the result to get the file.
+pub fn assemble(listing: &str) -> Result<QOIImage, AsmError> {
+    let mut header = None;
+    let mut data = Vec::new();
+    for (n, line) in listing.lines().enumerate() {
+        let bad = |reason| AsmError::BadLine {
+            line: n + 1,
+            reason,
+        };
+        let code = line.split(';').next().unwrap_or_default();
+        let mut words = code.split_whitespace().peekable();
+        if words.peek().is_some_and(|w| is_offset(w)) {
+            words.next();
+        }
+        let Some(mnemonic) = words.next() else {
+            continue;
+        };
+        let args: Vec<&str> = words.collect();
+        let mnemonic = mnemonic.to_ascii_uppercase();
+
+        if mnemonic == "HEADER" {
+            if header.is_some() {
+                return Err(bad(LineError::SecondHeader));
+            }
+            header = Some(read_header(&args).map_err(bad)?);
+            continue;
+        }
+        if header.is_none() {
+            return Err(AsmError::MissingHeader);
+        }
+        data.push(read_chunk(&mnemonic, &args).map_err(bad)?);
+    }
+    let header = header.ok_or(AsmError::MissingHeader)?;
+    Ok(QOIImage::from_parts(header, data))
+}
+
+/// Whether a word is the `offset:` label at the start of a line.
+fn is_offset(word: &str) -> bool {
+    word.strip_suffix(':')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn read_header(args: &[&str]) -> Result<Header, LineError> {
+    let mut fields = Fields::new(args)?;
+    let width = fields.number("width", 0..=u32::MAX as i64)? as u32;
+    let height = fields.number("height", 0..=u32::MAX as i64)? as u32;
+    let channels = match fields.number("channels", 3..=4)? {
+        3 => Channels::RGB,
+        _ => Channels::RGBA,
+    };
+    let color_space = match fields.take("colorspace")?.to_ascii_lowercase().as_str() {
+        "srgb" => ColorSpace::SRGB,
+        "linear" => ColorSpace::Linear,
+        _ => return Err(LineError::BadColorSpace),
+    };
+    fields.finish()?;
+    Ok(Header {
+        width,
+        height,
+        channels,
+        color_space,
+    })
+}
+
+fn read_chunk(mnemonic: &str, args: &[&str]) -> Result<Chunk, LineError> {
+    let chunk = match mnemonic {
+        "RGB" | "RGBA" => {
+            let mut fields = Fields::new(args)?;
+            let mut channel = |name| fields.number(name, 0..=255).map(|v| v as u8);
+            let (r, g, b) = (channel("r")?, channel("g")?, channel("b")?);
+            let chunk = if mnemonic == "RGB" {
+                Chunk::RGB(PixelRGB::new(r, g, b))
+            } else {
+                Chunk::RGBA(PixelRGBA::new(r, g, b, channel("a")?))
+            };
+            fields.finish()?;
+            chunk
+        }
+        "DIFF" => {
+            let mut fields = Fields::new(args)?;
+            let mut diff = |name| fields.number(name, -2..=1).map(|v| v as i8);
+            let (dr, dg, db) = (diff("dr")?, diff("dg")?, diff("db")?);
+            fields.finish()?;
+            Chunk::Diff(DiffRGB::new(dr, dg, db).ok_or(LineError::ValueOutOfRange)?)
+        }
+        "LUMA" => {
+            let mut fields = Fields::new(args)?;
+            let dg = fields.number("dg", -32..=31)? as i8;
+            let dr_dg = fields.number("dr-dg", -8..=7)? as i8;
+            let db_dg = fields.number("db-dg", -8..=7)? as i8;
+            fields.finish()?;
+            Chunk::Luma(Luma::new(dg, dr_dg, db_dg).ok_or(LineError::ValueOutOfRange)?)
+        }
+        "INDEX" => {
+            let out_of_range = LineError::IndexOutOfRange;
+            Chunk::Index(Index::new(operand(args, out_of_range)?).ok_or(out_of_range)?)
+        }
+        "RUN" => {
+            let out_of_range = LineError::RunOutOfRange;
+            Chunk::Run(Run::new(operand(args, out_of_range)?).ok_or(out_of_range)?)
+        }
+        _ => return Err(LineError::UnknownMnemonic),
+    };
+    Ok(chunk)
+}
+
+/// The single bare number `INDEX` and `RUN` take.
+fn operand(args: &[&str], out_of_range: LineError) -> Result<u8, LineError> {
+    match args {
+        [value] => match parse_number(value, 0..=255) {
+            Err(LineError::ValueOutOfRange) => Err(out_of_range),
+            number => number.map(|v| v as u8),
+        },
+        [] => Err(LineError::MissingOperand),
+        _ => Err(LineError::TooManyOperands),
+    }
+}
+
+fn parse_number(value: &str, range: std::ops::RangeInclusive<i64>) -> Result<i64, LineError> {
+    let value: i64 = value.parse().map_err(|_| LineError::NotANumber)?;
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(LineError::ValueOutOfRange)
+    }
+}
+
+/// The `name=value` arguments of a line, taken out one by one.
+struct Fields<'a> {
+    fields: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(args: &[&'a str]) -> Result<Self, LineError> {
+        let fields = args
+            .iter()
+            .map(|arg| arg.split_once('=').ok_or(LineError::NotAField))
+            .collect::<Result<_, _>>()?;
+        Ok(Fields { fields })
+    }
+
+    fn take(&mut self, name: &str) -> Result<&'a str, LineError> {
+        let mut found = self
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(_, (key, _))| key.eq_ignore_ascii_case(name))
+            .map(|(i, _)| i);
+        match (found.next(), found.next()) {
+            (Some(i), None) => Ok(self.fields.remove(i).1),
+            (None, _) => Err(LineError::MissingField),
+            (Some(_), Some(_)) => Err(LineError::RepeatedField),
+        }
+    }
+
+    fn number(
+        &mut self,
+        name: &str,
+        range: std::ops::RangeInclusive<i64>,
+    ) -> Result<i64, LineError> {
+        parse_number(self.take(name)?, range)
+    }
+
+    fn finish(self) -> Result<(), LineError> {
+        if self.fields.is_empty() {
+            Ok(())
+        } else {
+            Err(LineError::UnknownField)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dice_round_trip() {
+        let data = std::fs::read("files/dice.qoi").unwrap();
+        let image = QOIImage::from_reader(data.as_slice()).unwrap();
+        let listing = disassemble(&image);
+        assert!(listing.starts_with("HEADER width=800 height=600 channels=4 colorspace=srgb\n"));
+        assert_eq!(listing.lines().count(), image.chunk_count() + 2);
+        assert_eq!(assemble(&listing).unwrap().serialize(), data);
+    }
+
+    #[test]
+    fn hand_written() {
+        let listing = "\
+            ; every opcode once\n\
+            header Width=2 height=3 channels=4 colorspace=linear\n\
+            RGBA a=0 r=1 g=2 b=3\n\
+            99: RGB r=10 g=20 b=30 ; the offset is ignored\n\
+            DIFF dr=-2 dg=1 db=0\n\
+            LUMA dg=-32 dr-dg=7 db-dg=-8\n\
+            \n\
+            INDEX 63\n\
+            RUN 62\n";
+        let image = assemble(listing).unwrap();
+        let bytes = image.serialize();
+        assert_eq!(&bytes[4..14], &[0, 0, 0, 2, 0, 0, 0, 3, 4, 1]);
+        let expected: [&[u8]; 6] = [
+            &[0xff, 1, 2, 3, 0],
+            &[0xfe, 10, 20, 30],
+            &[0x40 | 0b00_11_10],
+            &[0x80, 0xf0],
+            &[63],
+            &[0xc0 | 61],
+        ];
+        assert_eq!(&bytes[14..bytes.len() - 8], expected.concat());
+        let infos: Vec<_> = image.chunks().collect();
+        // RGB keeps the alpha of the pixel before it
+        assert_eq!(infos[1].pixel, PixelRGBA::new(10, 20, 30, 0));
+        assert_eq!(infos[2].pixel, PixelRGBA::new(8, 21, 30, 0));
+    }
+
+    #[test]
+    fn short_listings() {
+        let listing = "\
+            HEADER width=2 height=2 channels=4 colorspace=srgb\n\
+            RGBA r=1 g=2 b=3 a=4\n\
+            RGB r=5 g=6 b=7\n";
+        let image = assemble(listing).unwrap();
+        assert!(matches!(
+            image.try_to_rgba_mat(),
+            Err(crate::QoiError::PixelCountMismatch {
+                expected: 4,
+                actual: 2
+            })
+        ));
+        // the rest repeats the last pixel, as the reference decoder has it
+        let last = PixelRGBA::new(5, 6, 7, 4);
+        assert_eq!(
+            image.to_rgba_mat(),
+            [[PixelRGBA::new(1, 2, 3, 4), last], [last, last]]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let header = "HEADER width=1 height=1 channels=3 colorspace=srgb\n";
+        let bad = |body: &str| assemble(&format!("{header}{body}")).err().unwrap();
+        let at = |line, reason| AsmError::BadLine { line, reason };
+
+        assert_eq!(assemble("RUN 1\n").err(), Some(AsmError::MissingHeader));
+        assert_eq!(assemble("; nothing\n").err(), Some(AsmError::MissingHeader));
+        assert_eq!(bad("RUN 63"), at(2, LineError::RunOutOfRange));
+        assert_eq!(bad("RUN 0"), at(2, LineError::RunOutOfRange));
+        assert_eq!(bad("INDEX 64"), at(2, LineError::IndexOutOfRange));
+        assert_eq!(bad("RUN"), at(2, LineError::MissingOperand));
+        assert_eq!(bad("INDEX 1 2"), at(2, LineError::TooManyOperands));
+        assert_eq!(bad("INDEX x"), at(2, LineError::NotANumber));
+        assert_eq!(
+            bad("\nDIFF dr=0 dg=2 db=0"),
+            at(3, LineError::ValueOutOfRange)
+        );
+        assert_eq!(bad("LUMA dg=0 dr-dg=0"), at(2, LineError::MissingField));
+        assert_eq!(bad("RGB r=0 r=0 g=0 b=0"), at(2, LineError::RepeatedField));
+        assert_eq!(bad("RGB r=0 g=0 b=0 a=0"), at(2, LineError::UnknownField));
+        assert_eq!(bad("RGB 0 0 0"), at(2, LineError::NotAField));
+        assert_eq!(bad("NOP"), at(2, LineError::UnknownMnemonic));
+        assert_eq!(bad(header), at(2, LineError::SecondHeader));
+        assert_eq!(
+            bad("LUMA dg=0 dr-dg=0").to_string(),
+            "Malformed QOI listing: line 2: missing field"
+        );
+        assert_eq!(
+            assemble("HEADER width=1 height=1 channels=2 colorspace=srgb").err(),
+            Some(at(1, LineError::ValueOutOfRange))
+        );
+    }
+}
@@ -0,0 +1,566 @@
+//! Command-line conversion between QOI files and PNG, Netpbm, BMP, TGA or raw
+//! pixel files.
+
+use std::{fmt, fs, process::ExitCode};
+
+use qoi_decode::{
+    asm, asm::AsmError, bmp, bmp::BmpError, decode_to_vec_with_options, encode_to_vec_with_mode,
+    png, png::PngError, pnm, pnm::PnmError, tga, tga::TgaError, Channels, ColorSpace,
+    DecodeOptions, EncodeMode, Header, Opcode, PixelRGBA, QOIImage, QoiError,
+};
+
+const USAGE: &str = "\
+usage:
+  qoi encode <in.png|in.ppm|in.pgm|in.pam|in.bmp|in.tga> <out.qoi> [--channels 3|4] [--srgb|--linear] [--reference]
+  qoi encode <in.rgba|in.rgb> <out.qoi> --width <w> --height <h> [--channels 3|4] [--srgb|--linear] [--reference]
+  qoi decode <in.qoi> <out.png|out.ppm|out.pgm|out.pam|out.bmp|out.tga|out.rgba|out.rgb>
+  qoi info <file.qoi>...
+  qoi verify <file.qoi>...
+  qoi disasm <in.qoi> [out.txt]
+  qoi asm <in.txt> <out.qoi>
+
+raw files hold packed 8-bit pixels, row by row; the extension gives the channel count.
+--reference writes exactly what the reference qoi.h encoder would.
+exit status: 0 on success, 1 if an image is malformed, 2 on bad usage, 3 on I/O errors.";
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Qoi(String, QoiError),
+    Png(String, PngError),
+    Pnm(String, PnmError),
+    Bmp(String, BmpError),
+    Tga(String, TgaError),
+    Asm(String, AsmError),
+    Io(String, std::io::Error),
+    /// Errors that were printed as they happened; holds the exit code.
+    Reported(u8),
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Qoi(_, QoiError::Io(_)) | CliError::Io(..) => 3,
+            CliError::Qoi(..)
+            | CliError::Png(..)
+            | CliError::Pnm(..)
+            | CliError::Bmp(..)
+            | CliError::Tga(..)
+            | CliError::Asm(..) => 1,
+            CliError::Usage(_) => 2,
+            CliError::Reported(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(msg) => write!(f, "{msg}\n\n{USAGE}"),
+            CliError::Qoi(path, e) => write!(f, "{path}: {e}"),
+            CliError::Png(path, e) => write!(f, "{path}: {e}"),
+            CliError::Pnm(path, e) => write!(f, "{path}: {e}"),
+            CliError::Bmp(path, e) => write!(f, "{path}: {e}"),
+            CliError::Tga(path, e) => write!(f, "{path}: {e}"),
+            CliError::Asm(path, e) => write!(f, "{path}: {e}"),
+            CliError::Io(path, e) => write!(f, "{path}: {e}"),
+            CliError::Reported(_) => Ok(()),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if !matches!(e, CliError::Reported(_)) {
+                eprintln!("qoi: {e}");
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), CliError> {
+    let (command, rest) = args
+        .split_first()
+        .ok_or_else(|| CliError::Usage("missing command".to_string()))?;
+    match command.as_str() {
+        "encode" => encode(rest),
+        "decode" => decode(rest),
+        "info" => each_file(rest, info),
+        "verify" => each_file(rest, verify),
+        "disasm" => disasm(rest),
+        "asm" => assemble(rest),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(CliError::Usage(format!("unknown command `{command}`"))),
+    }
+}
+
+/// Runs `f` over every file named, reporting each failure as it happens and
+/// exiting with the first failure's code once all of them have been tried.
+fn each_file(paths: &[String], f: fn(&str) -> Result<(), CliError>) -> Result<(), CliError> {
+    if paths.is_empty() {
+        return Err(CliError::Usage("no files given".to_string()));
+    }
+    let mut exit_code = None;
+    for path in paths {
+        if let Err(e) = f(path) {
+            eprintln!("qoi: {e}");
+            exit_code.get_or_insert(e.exit_code());
+        }
+    }
+    exit_code.map_or(Ok(()), |code| Err(CliError::Reported(code)))
+}
+
+/// The pixel file formats `encode` reads and `decode` writes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    /// Packed pixels with no header at all.
+    Raw(Channels),
+    Png,
+    /// Any Netpbm format when reading; written in the one given.
+    Pnm(pnm::Format),
+    Bmp,
+    /// Run-length encoded when written.
+    Tga,
+}
+
+/// The format of a pixel file, from its extension.
+fn format_of(path: &str) -> Result<Format, CliError> {
+    match path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("rgba") => Ok(Format::Raw(Channels::RGBA)),
+        Some("rgb") => Ok(Format::Raw(Channels::RGB)),
+        Some("png") => Ok(Format::Png),
+        Some("ppm" | "pnm") => Ok(Format::Pnm(pnm::Format::Ppm)),
+        Some("pgm") => Ok(Format::Pnm(pnm::Format::Pgm)),
+        Some("pam") => Ok(Format::Pnm(pnm::Format::Pam)),
+        Some("bmp") => Ok(Format::Bmp),
+        Some("tga") => Ok(Format::Tga),
+        _ => Err(CliError::Usage(format!(
+            "`{path}` needs a .png, .ppm, .pgm, .pam, .bmp, .tga, .rgb or .rgba extension"
+        ))),
+    }
+}
+
+/// Reads a pixel file as packed pixels. Raw files have no header of their
+/// own, so `raw_header` supplies it.
+fn read_pixels(
+    path: &str,
+    format: Format,
+    raw_header: impl FnOnce(Channels) -> Result<Header, CliError>,
+) -> Result<(Header, Vec<u8>), CliError> {
+    let data = read(path)?;
+    match format {
+        Format::Raw(channels) => Ok((raw_header(channels)?, data)),
+        Format::Png => png::decode(&data).map_err(|e| CliError::Png(path.to_string(), e)),
+        Format::Pnm(_) => pnm::decode(&data).map_err(|e| CliError::Pnm(path.to_string(), e)),
+        Format::Bmp => bmp::decode(&data)
+            .map(|mat| from_mat(&mat))
+            .map_err(|e| CliError::Bmp(path.to_string(), e)),
+        Format::Tga => tga::decode(&data)
+            .map(|mat| from_mat(&mat))
+            .map_err(|e| CliError::Tga(path.to_string(), e)),
+    }
+}
+
+fn write_pixels(path: &str, format: Format, header: Header, pixels: &[u8]) -> Result<(), CliError> {
+    match format {
+        Format::Raw(channels) => write(path, &convert(pixels, header.channels, channels)),
+        Format::Png => {
+            let encoded =
+                png::encode(pixels, header).map_err(|e| CliError::Png(path.to_string(), e))?;
+            write(path, &encoded)
+        }
+        Format::Pnm(format) => {
+            let encoded = pnm::encode(pixels, header, format)
+                .map_err(|e| CliError::Pnm(path.to_string(), e))?;
+            write(path, &encoded)
+        }
+        Format::Bmp => {
+            let encoded = bmp::encode(&to_mat(pixels, header))
+                .map_err(|e| CliError::Bmp(path.to_string(), e))?;
+            write(path, &encoded)
+        }
+        Format::Tga => {
+            let encoded = tga::encode(&to_mat(pixels, header), tga::Compression::Rle)
+                .map_err(|e| CliError::Tga(path.to_string(), e))?;
+            write(path, &encoded)
+        }
+    }
+}
+
+/// Packs rows of pixels, as RGB if every pixel is opaque.
+fn from_mat(mat: &[Vec<PixelRGBA>]) -> (Header, Vec<u8>) {
+    let alpha = mat.iter().flatten().any(|px| px.a != 255);
+    let header = Header {
+        width: mat.first().map_or(0, Vec::len) as u32,
+        height: mat.len() as u32,
+        channels: if alpha { Channels::RGBA } else { Channels::RGB },
+        color_space: ColorSpace::SRGB,
+    };
+    let pixels = mat
+        .iter()
+        .flatten()
+        .flat_map(|px| [px.r, px.g, px.b, px.a])
+        .collect::<Vec<u8>>();
+    (header, convert(&pixels, Channels::RGBA, header.channels))
+}
+
+fn to_mat(pixels: &[u8], header: Header) -> Vec<Vec<PixelRGBA>> {
+    let channels = header.channels.count();
+    let row_len = header.width as usize * channels;
+    if row_len == 0 {
+        return vec![Vec::new(); header.height as usize];
+    }
+    pixels
+        .chunks_exact(row_len)
+        .map(|row| {
+            row.chunks_exact(channels)
+                .map(|px| PixelRGBA::new(px[0], px[1], px[2], px.get(3).copied().unwrap_or(255)))
+                .collect()
+        })
+        .collect()
+}
+
+fn read(path: &str) -> Result<Vec<u8>, CliError> {
+    fs::read(path).map_err(|e| CliError::Io(path.to_string(), e))
+}
+
+fn write(path: &str, data: &[u8]) -> Result<(), CliError> {
+    fs::write(path, data).map_err(|e| CliError::Io(path.to_string(), e))
+}
+
+struct EncodeArgs {
+    input: String,
+    output: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    channels: Option<Channels>,
+    color_space: Option<ColorSpace>,
+    mode: EncodeMode,
+}
+
+fn parse_encode_args(args: &[String]) -> Result<EncodeArgs, CliError> {
+    let mut paths = Vec::new();
+    let mut width = None;
+    let mut height = None;
+    let mut channels = None;
+    let mut color_space = None;
+    let mut mode = EncodeMode::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| CliError::Usage(format!("{name} needs a value")))
+        };
+        match arg.as_str() {
+            "--width" => width = Some(parse_number(value("--width")?)?),
+            "--height" => height = Some(parse_number(value("--height")?)?),
+            "--channels" => {
+                channels = Some(match value("--channels")?.as_str() {
+                    "3" => Channels::RGB,
+                    "4" => Channels::RGBA,
+                    n => return Err(CliError::Usage(format!("channels must be 3 or 4, not {n}"))),
+                })
+            }
+            "--srgb" => color_space = Some(ColorSpace::SRGB),
+            "--linear" => color_space = Some(ColorSpace::Linear),
+            "--reference" => mode = EncodeMode::ReferenceCompatible,
+            flag if flag.starts_with("--") => {
+                return Err(CliError::Usage(format!("unknown option `{flag}`")))
+            }
+            path => paths.push(path.to_string()),
+        }
+    }
+
+    let [input, output]: [String; 2] = paths
+        .try_into()
+        .map_err(|_| CliError::Usage("encode takes an input and an output file".to_string()))?;
+    Ok(EncodeArgs {
+        input,
+        output,
+        width,
+        height,
+        channels,
+        color_space,
+        mode,
+    })
+}
+
+fn parse_number(value: &str) -> Result<u32, CliError> {
+    value
+        .parse()
+        .map_err(|_| CliError::Usage(format!("`{value}` is not a valid size")))
+}
+
+fn encode(args: &[String]) -> Result<(), CliError> {
+    let args = parse_encode_args(args)?;
+    let format = format_of(&args.input)?;
+    let (header, pixels) = read_pixels(&args.input, format, |channels| {
+        let required = |name| CliError::Usage(format!("{name} is required for raw input"));
+        Ok(Header {
+            width: args.width.ok_or_else(|| required("--width"))?,
+            height: args.height.ok_or_else(|| required("--height"))?,
+            channels,
+            color_space: ColorSpace::SRGB,
+        })
+    })?;
+
+    let channels = args.channels.unwrap_or(header.channels);
+    let pixels = convert(&pixels, header.channels, channels);
+    let header = Header {
+        channels,
+        color_space: args.color_space.unwrap_or(header.color_space),
+        ..header
+    };
+    let encoded = encode_to_vec_with_mode(&pixels, header, args.mode)
+        .map_err(|e| CliError::Qoi(args.input.clone(), e))?;
+    write(&args.output, &encoded)
+}
+
+fn decode(args: &[String]) -> Result<(), CliError> {
+    let [input, output] = args else {
+        return Err(CliError::Usage(
+            "decode takes an input and an output file".to_string(),
+        ));
+    };
+    let format = format_of(output)?;
+    let data = read(input)?;
+    let (header, pixels) = decode_to_vec_with_options(&data, &DecodeOptions::default())
+        .map_err(|e| CliError::Qoi(input.clone(), e))?;
+    write_pixels(output, format, header, &pixels)
+}
+
+/// Repacks pixels between three and four channels; added alpha is opaque.
+fn convert(pixels: &[u8], from: Channels, to: Channels) -> Vec<u8> {
+    match (from, to) {
+        (Channels::RGBA, Channels::RGB) => pixels
+            .chunks_exact(4)
+            .flat_map(|px| [px[0], px[1], px[2]])
+            .collect(),
+        (Channels::RGB, Channels::RGBA) => pixels
+            .chunks_exact(3)
+            .flat_map(|px| [px[0], px[1], px[2], 255])
+            .collect(),
+        _ => pixels.to_vec(),
+    }
+}
+
+fn info(path: &str) -> Result<(), CliError> {
+    let data = read(path)?;
+    let image =
+        QOIImage::from_reader(data.as_slice()).map_err(|e| CliError::Qoi(path.to_string(), e))?;
+    let header = image.header();
+
+    let channels = match header.channels {
+        Channels::RGB => "RGB",
+        Channels::RGBA => "RGBA",
+    };
+    let color_space = match header.color_space {
+        ColorSpace::SRGB => "sRGB",
+        ColorSpace::Linear => "linear",
+    };
+    let pixels = header.pixel_count();
+    let raw_size = pixels * header.channels.count() as u64;
+    println!(
+        "{path}: {}x{} {channels}, {color_space}",
+        header.width, header.height
+    );
+    println!(
+        "  chunks: {} ({:.2} pixels per chunk)",
+        image.chunk_count(),
+        pixels as f64 / image.chunk_count().max(1) as f64
+    );
+    println!(
+        "  size: {} bytes, {:.3} bytes per pixel, {:.1}% of raw",
+        data.len(),
+        data.len() as f64 / pixels.max(1) as f64,
+        data.len() as f64 * 100.0 / raw_size.max(1) as f64
+    );
+
+    let stats = image.chunk_stats();
+    println!("  opcode      chunks     bytes   share    pixels");
+    for opcode in Opcode::ALL {
+        let op = stats.opcode(opcode);
+        println!(
+            "  {:<6} {:>11} {:>9} {:>6.1}% {:>9}",
+            format!("{opcode:?}").to_uppercase(),
+            op.count,
+            op.bytes,
+            stats.byte_share(opcode) * 100.0,
+            op.pixels
+        );
+    }
+    println!(
+        "  average run: {:.2} pixels, index hit rate: {:.1}%",
+        stats.average_run_length(),
+        stats.index_hit_rate() * 100.0
+    );
+    Ok(())
+}
+
+fn verify(path: &str) -> Result<(), CliError> {
+    let data = read(path)?;
+    let options = DecodeOptions {
+        strict: true,
+        ..DecodeOptions::default()
+    };
+    decode_to_vec_with_options(&data, &options).map_err(|e| CliError::Qoi(path.to_string(), e))?;
+    println!("{path}: ok");
+    Ok(())
+}
+
+/// Writes a chunk listing to the output file, or to stdout if none is given.
+fn disasm(args: &[String]) -> Result<(), CliError> {
+    let (input, output) = match args {
+        [input] => (input, None),
+        [input, output] => (input, Some(output)),
+        _ => {
+            return Err(CliError::Usage(
+                "disasm takes an input and an optional output file".to_string(),
+            ))
+        }
+    };
+    let data = read(input)?;
+    let image =
+        QOIImage::from_reader(data.as_slice()).map_err(|e| CliError::Qoi(input.clone(), e))?;
+    let listing = asm::disassemble(&image);
+    match output {
+        Some(output) => write(output, listing.as_bytes()),
+        None => {
+            print!("{listing}");
+            Ok(())
+        }
+    }
+}
+
+fn assemble(args: &[String]) -> Result<(), CliError> {
+    let [input, output] = args else {
+        return Err(CliError::Usage(
+            "asm takes an input and an output file".to_string(),
+        ));
+    };
+    let listing = fs::read_to_string(input).map_err(|e| CliError::Io(input.clone(), e))?;
+    let image = asm::assemble(&listing).map_err(|e| CliError::Asm(input.clone(), e))?;
+    write(output, &image.serialize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn encode_args() {
+        let parsed = parse_encode_args(&args(
+            "in.rgb out.qoi --width 3 --height 2 --linear --channels 4 --reference",
+        ))
+        .unwrap();
+        assert_eq!(
+            (parsed.input.as_str(), parsed.output.as_str()),
+            ("in.rgb", "out.qoi")
+        );
+        assert_eq!((parsed.width, parsed.height), (Some(3), Some(2)));
+        assert_eq!(parsed.channels, Some(Channels::RGBA));
+        assert_eq!(parsed.color_space, Some(ColorSpace::Linear));
+        assert_eq!(parsed.mode, EncodeMode::ReferenceCompatible);
+
+        for bad in [
+            "in.rgb --width 3 --height 2",
+            "in.rgb out.qoi --width x --height 2",
+            "in.rgb out.qoi --width 3 --height 2 --channels 5",
+            "in.rgb out.qoi --width 3 --height 2 --bogus",
+        ] {
+            assert!(matches!(
+                parse_encode_args(&args(bad)),
+                Err(CliError::Usage(_))
+            ));
+        }
+    }
+
+    /// A fresh scratch directory for one test.
+    fn temp_dir(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("qoi-cli-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn exit_codes() {
+        let dir = temp_dir("exit-codes");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        let qoi = path("testcard.qoi");
+        let raw = path("testcard.rgba");
+        let cmd = format!("encode files/testcard_rgba.rgba {qoi} --width 256 --height 256");
+        assert!(run(&args(&cmd)).is_ok());
+        assert!(run(&args(&format!("verify {qoi}"))).is_ok());
+        assert!(run(&args(&format!("decode {qoi} {raw}"))).is_ok());
+        assert!(read(&raw).unwrap() == read("files/testcard_rgba.rgba").unwrap());
+
+        write(&path("broken.qoi"), b"qoif").unwrap();
+        let err = run(&args(&format!("verify {qoi} {}", path("broken.qoi")))).unwrap_err();
+        assert_eq!(err.exit_code(), 1);
+        let err = run(&args(&format!("info {}", path("missing.qoi")))).unwrap_err();
+        assert_eq!(err.exit_code(), 3);
+        assert_eq!(run(&args("frobnicate")).unwrap_err().exit_code(), 2);
+        let cmd = format!("encode files/testcard_rgba.rgba {qoi} --width 256");
+        assert_eq!(run(&args(&cmd)).unwrap_err().exit_code(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn image_formats() {
+        let dir = temp_dir("formats");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        let (png, qoi, raw) = (path("dice.png"), path("dice.qoi"), path("dice.rgba"));
+        assert!(run(&args(&format!("decode files/dice.qoi {png}"))).is_ok());
+        assert!(run(&args(&format!("encode {png} {qoi}"))).is_ok());
+        assert!(run(&args(&format!("decode {qoi} {raw}"))).is_ok());
+        let (_, expected) =
+            decode_to_vec_with_options(&read("files/dice.qoi").unwrap(), &DecodeOptions::default())
+                .unwrap();
+        assert!(read(&raw).unwrap() == expected);
+
+        let pam = path("dice.pam");
+        assert!(run(&args(&format!("decode files/dice.qoi {pam}"))).is_ok());
+        assert!(run(&args(&format!("encode {pam} {qoi}"))).is_ok());
+        assert!(read(&qoi).unwrap() == read("files/dice.qoi").unwrap());
+
+        for ext in ["bmp", "tga"] {
+            let file = path(&format!("dice.{ext}"));
+            assert!(run(&args(&format!("decode files/dice.qoi {file}"))).is_ok());
+            assert!(run(&args(&format!("encode {file} {qoi}"))).is_ok());
+            assert!(read(&qoi).unwrap() == read("files/dice.qoi").unwrap());
+        }
+
+        let listing = path("dice.txt");
+        assert!(run(&args(&format!("disasm files/dice.qoi {listing}"))).is_ok());
+        assert!(run(&args(&format!("asm {listing} {qoi}"))).is_ok());
+        assert!(read(&qoi).unwrap() == read("files/dice.qoi").unwrap());
+        write(&listing, b"RUN 1").unwrap();
+        let err = run(&args(&format!("asm {listing} {qoi}"))).unwrap_err();
+        assert!(matches!(err, CliError::Asm(_, AsmError::MissingHeader)));
+
+        write(&png, b"not a png").unwrap();
+        let err = run(&args(&format!("encode {png} {qoi}"))).unwrap_err();
+        assert!(matches!(err, CliError::Png(_, PngError::BadSignature)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,450 @@
+//! Windows BMP import and export, to and from the row-major pixel matrices
+//! that [`QOIImage::to_rgba_mat`](crate::QOIImage::to_rgba_mat) and
+//! [`QOIImage::from_rgba_mat`](crate::QOIImage::from_rgba_mat) use.
+//!
+//! 24-bit and 32-bit images are read, stored bottom-up or top-down, with any
+//! info header from BITMAPINFOHEADER to BITMAPV5HEADER. 32-bit pixels may
+//! use bitfield masks. Files are written bottom-up, as 24-bit when every
+//! pixel is opaque and as 32-bit with an alpha mask otherwise.
+
+use std::fmt;
+
+use crate::{Channels, ColorSpace, Header, LimitKind, Limits, PixelRGBA, QoiError};
+
+const FILE_HEADER_LEN: usize = 14;
+const INFO_HEADER_LEN: u32 = 40;
+const V4_HEADER_LEN: u32 = 108;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+// "sRGB", the color space tag of a V4 header
+const LCS_SRGB: u32 = 0x7352_4742;
+// 72 DPI, in the pixels per metre BMP records resolution in
+const PIXELS_PER_METRE: u32 = 2835;
+
+/// Everything that can go wrong while reading a BMP file.
+#[derive(Debug)]
+pub enum BmpError {
+    /// The file does not start with `BM`.
+    BadSignature { found: [u8; 2] },
+    /// A header field is missing or not a valid value.
+    BadHeader(&'static str),
+    /// The bit depth or compression is not one this module reads.
+    Unsupported {
+        bits_per_pixel: u16,
+        compression: u32,
+    },
+    /// The pixel data ended before the last row.
+    TruncatedData,
+    /// The image is too large for the limits, or too large to store as BMP.
+    Image(QoiError),
+}
+
+impl fmt::Display for BmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BmpError::BadSignature { found } => {
+                write!(f, "Malformed BMP: signature not found (got {found:02x?})")
+            }
+            BmpError::BadHeader(msg) => write!(f, "Malformed BMP: {msg}"),
+            BmpError::Unsupported {
+                bits_per_pixel,
+                compression,
+            } => write!(
+                f,
+                "Unsupported BMP: {bits_per_pixel} bits per pixel with compression {compression}"
+            ),
+            BmpError::TruncatedData => write!(f, "Malformed BMP: pixel data ends early"),
+            BmpError::Image(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BmpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BmpError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<QoiError> for BmpError {
+    fn from(e: QoiError) -> Self {
+        BmpError::Image(e)
+    }
+}
+
+/// Decodes a BMP into rows of pixels, top row first, with the default
+/// [`Limits`].
+pub fn decode(data: &[u8]) -> Result<Vec<Vec<PixelRGBA>>, BmpError> {
+    decode_with_limits(data, Limits::default())
+}
+
+/// Like [`decode`], but rejects images the `limits` do not allow before
+/// reading any pixels.
+pub fn decode_with_limits(data: &[u8], limits: Limits) -> Result<Vec<Vec<PixelRGBA>>, BmpError> {
+    match *data {
+        [b'B', b'M', ..] => {}
+        [a, b, ..] => return Err(BmpError::BadSignature { found: [a, b] }),
+        _ => return Err(BmpError::BadHeader("file is too short")),
+    }
+    let u16_at = |pos: usize| {
+        data.get(pos..pos + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let u32_at = |pos: usize| {
+        data.get(pos..pos + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let field = |pos: usize| u32_at(pos).ok_or(BmpError::BadHeader("header is too short"));
+
+    let pixel_offset = field(10)? as usize;
+    let info_len = field(14)?;
+    if !(INFO_HEADER_LEN..=124).contains(&info_len) {
+        return Err(BmpError::BadHeader("unknown info header size"));
+    }
+    let width = field(18)? as i32;
+    let height = field(22)? as i32;
+    let bits_per_pixel = u16_at(28).ok_or(BmpError::BadHeader("header is too short"))?;
+    let compression = field(30)?;
+    if width < 0 {
+        return Err(BmpError::BadHeader("negative width"));
+    }
+    // a negative height means the rows are stored top row first
+    let top_down = height < 0;
+    let (width, height) = (width as u32, height.unsigned_abs());
+
+    // masks follow a plain info header, or sit at the same place inside
+    // the larger ones
+    let masks = match (bits_per_pixel, compression) {
+        (24, BI_RGB) => None,
+        (32, BI_RGB) => Some([0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000]),
+        (32, BI_BITFIELDS | BI_ALPHABITFIELDS) => {
+            let alpha = if compression == BI_ALPHABITFIELDS || info_len >= 56 {
+                field(FILE_HEADER_LEN + 52)?
+            } else {
+                0
+            };
+            let masks = [
+                field(FILE_HEADER_LEN + 40)?,
+                field(FILE_HEADER_LEN + 44)?,
+                field(FILE_HEADER_LEN + 48)?,
+                alpha,
+            ];
+            if !masks.iter().all(|&mask| is_contiguous(mask)) {
+                return Err(BmpError::BadHeader("bitfield mask is not contiguous"));
+            }
+            Some(masks)
+        }
+        _ => {
+            return Err(BmpError::Unsupported {
+                bits_per_pixel,
+                compression,
+            })
+        }
+    };
+
+    limits.check(&Header {
+        width,
+        height,
+        channels: Channels::RGBA,
+        color_space: ColorSpace::SRGB,
+    })?;
+
+    let bytes_per_pixel = bits_per_pixel as usize / 8;
+    let stride = row_stride(width as usize, bytes_per_pixel);
+    let pixels = data
+        .get(pixel_offset..)
+        .and_then(|rest| rest.get(..stride.checked_mul(height as usize)?))
+        .ok_or(BmpError::TruncatedData)?;
+
+    // as with to_rgba_mat, a zero width image has no rows
+    let mut rows: Vec<Vec<PixelRGBA>> = pixels
+        .chunks_exact(stride.max(1))
+        .take(height as usize)
+        .map(|row| {
+            row.chunks_exact(bytes_per_pixel)
+                .take(width as usize)
+                .map(|px| match masks {
+                    None => PixelRGBA::new(px[2], px[1], px[0], 255),
+                    Some(masks) => {
+                        let value = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+                        let [r, g, b, a] = masks.map(|mask| extract(value, mask));
+                        PixelRGBA::new(
+                            r.unwrap_or(0),
+                            g.unwrap_or(0),
+                            b.unwrap_or(0),
+                            a.unwrap_or(255),
+                        )
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    // plenty of writers leave the fourth byte of BI_RGB pixels at zero, so
+    // read it as alpha only if some pixel uses it
+    if compression == BI_RGB && rows.iter().flatten().all(|px| px.a == 0) {
+        rows.iter_mut().flatten().for_each(|px| px.a = 255);
+    }
+    if !top_down {
+        rows.reverse();
+    }
+    Ok(rows)
+}
+
+/// Encodes rows of pixels, top row first, as a BMP.
+///
+/// # Panics
+///
+/// If the rows are not all the same length.
+pub fn encode(src: &[Vec<PixelRGBA>]) -> Result<Vec<u8>, BmpError> {
+    let width = src.first().map_or(0, Vec::len);
+    assert!(
+        src.iter().all(|row| row.len() == width),
+        "rows are not all the same length"
+    );
+    let too_big = |kind, value: usize| QoiError::LimitExceeded {
+        kind,
+        value: value as u64,
+        max: i32::MAX as u64,
+    };
+    if width > i32::MAX as usize {
+        return Err(too_big(LimitKind::Width, width).into());
+    }
+    if src.len() > i32::MAX as usize {
+        return Err(too_big(LimitKind::Height, src.len()).into());
+    }
+
+    let alpha = src.iter().flatten().any(|px| px.a != 255);
+    let (bytes_per_pixel, info_len, compression) = if alpha {
+        (4, V4_HEADER_LEN, BI_BITFIELDS)
+    } else {
+        (3, INFO_HEADER_LEN, BI_RGB)
+    };
+    let stride = row_stride(width, bytes_per_pixel);
+    let pixel_offset = FILE_HEADER_LEN as u32 + info_len;
+    let image_len = stride * src.len();
+    let file_len = pixel_offset as usize + image_len;
+    if file_len > u32::MAX as usize {
+        return Err(QoiError::LimitExceeded {
+            kind: LimitKind::Bytes,
+            value: file_len as u64,
+            max: u32::MAX as u64,
+        }
+        .into());
+    }
+
+    let mut res = Vec::with_capacity(file_len);
+    let put = |res: &mut Vec<u8>, value: u32| res.extend_from_slice(&value.to_le_bytes());
+    res.extend_from_slice(b"BM");
+    put(&mut res, file_len as u32);
+    put(&mut res, 0);
+    put(&mut res, pixel_offset);
+
+    put(&mut res, info_len);
+    put(&mut res, width as u32);
+    // positive height: bottom row first
+    put(&mut res, src.len() as u32);
+    res.extend_from_slice(&1u16.to_le_bytes());
+    res.extend_from_slice(&(bytes_per_pixel as u16 * 8).to_le_bytes());
+    put(&mut res, compression);
+    put(&mut res, image_len as u32);
+    put(&mut res, PIXELS_PER_METRE);
+    put(&mut res, PIXELS_PER_METRE);
+    // no palette, and every color is important
+    put(&mut res, 0);
+    put(&mut res, 0);
+    if alpha {
+        for mask in [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000] {
+            put(&mut res, mask);
+        }
+        put(&mut res, LCS_SRGB);
+        // endpoints and gamma, unused for sRGB
+        res.resize(res.len() + 48, 0);
+    }
+
+    for row in src.iter().rev() {
+        let start = res.len();
+        for px in row {
+            res.extend_from_slice(&[px.b, px.g, px.r]);
+            if alpha {
+                res.push(px.a);
+            }
+        }
+        res.resize(start + stride, 0);
+    }
+    Ok(res)
+}
+
+/// Rows are padded to a multiple of four bytes.
+fn row_stride(width: usize, bytes_per_pixel: usize) -> usize {
+    (width * bytes_per_pixel).div_ceil(4) * 4
+}
+
+/// Whether the set bits of `mask` form a single run. An empty mask does.
+fn is_contiguous(mask: u32) -> bool {
+    let shifted = mask.checked_shr(mask.trailing_zeros()).unwrap_or(0);
+    shifted.count_ones() == shifted.trailing_ones()
+}
+
+/// Pulls the channel under `mask`, which must be contiguous, out of a pixel
+/// and scales it to 8 bits. An empty mask means the channel is absent.
+fn extract(value: u32, mask: u32) -> Option<u8> {
+    if mask == 0 {
+        return None;
+    }
+    let bits = (mask >> mask.trailing_zeros()).trailing_ones();
+    // at most 32 bits, so scaling by 255 cannot overflow a u64
+    let v = u64::from((value & mask) >> mask.trailing_zeros());
+    Some(match bits {
+        8 => v as u8,
+        1..=7 => {
+            // round to nearest; the largest value must land on 255, not wrap
+            let max = (1 << bits) - 1;
+            ((v * 255 + max / 2) / max) as u8
+        }
+        _ => (v >> (bits - 8)) as u8,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice_mat;
+
+    /// A BMP with a BITMAPINFOHEADER and the given pixel rows as stored.
+    fn bmp(width: i32, height: i32, bits: u16, compression: u32, rows: &[&[u8]]) -> Vec<u8> {
+        let mut res = b"BM".to_vec();
+        res.extend_from_slice(&[0; 8]);
+        res.extend_from_slice(&54u32.to_le_bytes());
+        res.extend_from_slice(&40u32.to_le_bytes());
+        res.extend_from_slice(&width.to_le_bytes());
+        res.extend_from_slice(&height.to_le_bytes());
+        res.extend_from_slice(&1u16.to_le_bytes());
+        res.extend_from_slice(&bits.to_le_bytes());
+        res.extend_from_slice(&compression.to_le_bytes());
+        res.extend_from_slice(&[0; 20]);
+        for row in rows {
+            res.extend_from_slice(row);
+        }
+        res
+    }
+
+    #[test]
+    fn round_trips() {
+        let opaque: Vec<Vec<PixelRGBA>> = dice_mat()
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|px| PixelRGBA { a: 255, ..px })
+                    .collect()
+            })
+            .collect();
+        for src in [dice_mat(), opaque] {
+            let encoded = encode(&src).unwrap();
+            assert_eq!(decode(&encoded).unwrap(), src);
+        }
+
+        // odd widths need row padding
+        let src = vec![vec![PixelRGBA::new(1, 2, 3, 255); 3]; 2];
+        let encoded = encode(&src).unwrap();
+        assert_eq!(encoded.len(), 54 + 2 * 12);
+        assert_eq!(decode(&encoded).unwrap(), src);
+    }
+
+    #[test]
+    fn orientation_and_channel_order() {
+        // 24-bit, bottom-up: the first stored row is the bottom one
+        let data = bmp(1, 2, 24, BI_RGB, &[&[1, 2, 3, 0], &[4, 5, 6, 0]]);
+        assert_eq!(
+            decode(&data).unwrap(),
+            [
+                [PixelRGBA::new(6, 5, 4, 255)],
+                [PixelRGBA::new(3, 2, 1, 255)]
+            ]
+        );
+        // top-down
+        let data = bmp(1, -2, 24, BI_RGB, &[&[1, 2, 3, 0], &[4, 5, 6, 0]]);
+        assert_eq!(
+            decode(&data).unwrap(),
+            [
+                [PixelRGBA::new(3, 2, 1, 255)],
+                [PixelRGBA::new(6, 5, 4, 255)]
+            ]
+        );
+
+        // 32-bit BI_RGB with an unused fourth byte is opaque, but not when
+        // some pixel uses it
+        let data = bmp(2, 1, 32, BI_RGB, &[&[1, 2, 3, 0, 4, 5, 6, 0]]);
+        assert!(decode(&data).unwrap()[0].iter().all(|px| px.a == 255));
+        let data = bmp(2, 1, 32, BI_RGB, &[&[1, 2, 3, 0, 4, 5, 6, 9]]);
+        assert_eq!(decode(&data).unwrap()[0][1], PixelRGBA::new(6, 5, 4, 9));
+    }
+
+    #[test]
+    fn bitfields() {
+        // masks after a plain info header; red in the low byte, no alpha
+        let mut data = bmp(1, 1, 32, BI_BITFIELDS, &[]);
+        for mask in [0x0000_00ffu32, 0x0000_ff00, 0x00ff_0000] {
+            data.extend_from_slice(&mask.to_le_bytes());
+        }
+        data[10] = 66;
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(decode(&data).unwrap(), [[PixelRGBA::new(1, 2, 3, 255)]]);
+
+        assert_eq!(extract(0xf800, 0xf800), Some(255));
+        assert_eq!(extract(0x8000, 0xf800), Some(132));
+        assert_eq!(extract(u32::MAX, u32::MAX), Some(255));
+        assert_eq!(extract(1, 1), Some(255));
+
+        // a mask with a gap has no single scale
+        let mut data = bmp(1, 1, 32, BI_BITFIELDS, &[]);
+        for mask in [0x8000_0001u32, 0x0000_ff00, 0x00ff_0000] {
+            data.extend_from_slice(&mask.to_le_bytes());
+        }
+        data[10] = 66;
+        data.extend_from_slice(&[0xff; 4]);
+        assert!(matches!(decode(&data), Err(BmpError::BadHeader(_))));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            decode(b"PM\0\0"),
+            Err(BmpError::BadSignature { found }) if &found == b"PM"
+        ));
+        assert!(matches!(decode(b"BM"), Err(BmpError::BadHeader(_))));
+        assert!(matches!(
+            decode(&bmp(1, 1, 8, BI_RGB, &[&[0; 4]])),
+            Err(BmpError::Unsupported {
+                bits_per_pixel: 8,
+                ..
+            })
+        ));
+        assert!(matches!(
+            decode(&bmp(2, 2, 24, BI_RGB, &[&[0; 8]])),
+            Err(BmpError::TruncatedData)
+        ));
+        let tiny = Limits {
+            max_height: 1,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode_with_limits(&bmp(1, -2, 24, BI_RGB, &[&[0; 8]]), tiny),
+            Err(BmpError::Image(QoiError::LimitExceeded { .. }))
+        ));
+
+        // no pixels, but two billion rows of them
+        assert!(matches!(
+            decode(&bmp(0, i32::MIN, 24, BI_RGB, &[])),
+            Err(BmpError::Image(QoiError::LimitExceeded {
+                kind: LimitKind::Height,
+                ..
+            }))
+        ));
+        let rows = decode_with_limits(&bmp(0, i32::MIN, 24, BI_RGB, &[]), Limits::none());
+        assert!(rows.unwrap().is_empty());
+    }
+}
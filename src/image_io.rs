@@ -0,0 +1,243 @@
+//! Adapters that plug this crate into the `image` crate's decoding and
+//! encoding machinery.
+
+use std::io::{Read, Write};
+
+use image::error::{
+    DecodingError, EncodingError, ImageFormatHint, LimitError, LimitErrorKind, UnsupportedError,
+    UnsupportedErrorKind,
+};
+use image::{ColorType, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError, ImageFormat};
+
+use crate::{
+    decode_to_vec_with_options, encode_chunks, packed_pixels, read_header, Channels, DecodeOptions,
+    EncodeOptions, Header, QOIImage, QoiError,
+};
+
+const FORMAT: ImageFormatHint = ImageFormatHint::Exact(ImageFormat::Qoi);
+
+fn decoding_error(e: QoiError) -> ImageError {
+    match e {
+        QoiError::Io(e) => ImageError::IoError(e),
+        QoiError::LimitExceeded { .. } => {
+            ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError))
+        }
+        e => ImageError::Decoding(DecodingError::new(FORMAT, e)),
+    }
+}
+
+fn encoding_error(e: QoiError) -> ImageError {
+    match e {
+        QoiError::Io(e) => ImageError::IoError(e),
+        QoiError::LimitExceeded { .. } => {
+            ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError))
+        }
+        e => ImageError::Encoding(EncodingError::new(FORMAT, e)),
+    }
+}
+
+/// An [`ImageDecoder`] for QOI files. The whole stream is read and decoded
+/// when the decoder is created, so the dimensions and color type are known up
+/// front. The header is checked against the limits before the rest of the
+/// stream is read.
+pub struct QoiDecoder {
+    header: Header,
+    pixels: Vec<u8>,
+}
+
+impl QoiDecoder {
+    pub fn new<R: Read>(source: R) -> image::ImageResult<QoiDecoder> {
+        QoiDecoder::with_options(source, &DecodeOptions::default())
+    }
+
+    pub fn with_options<R: Read>(
+        mut source: R,
+        options: &DecodeOptions,
+    ) -> image::ImageResult<QoiDecoder> {
+        let header = read_header(&mut source).map_err(decoding_error)?;
+        options.limits.check(&header).map_err(decoding_error)?;
+        let mut data = header.to_bytes().to_vec();
+        source.read_to_end(&mut data).map_err(ImageError::IoError)?;
+        let (header, pixels) =
+            decode_to_vec_with_options(&data, options).map_err(decoding_error)?;
+        Ok(QoiDecoder { header, pixels })
+    }
+}
+
+impl ImageDecoder for QoiDecoder {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.header.width, self.header.height)
+    }
+
+    fn color_type(&self) -> ColorType {
+        match self.header.channels {
+            Channels::RGB => ColorType::Rgb8,
+            Channels::RGBA => ColorType::Rgba8,
+        }
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> image::ImageResult<()> {
+        // the image crate always hands over a buffer of total_bytes()
+        buf.copy_from_slice(&self.pixels);
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> image::ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+/// An [`ImageEncoder`] for QOI files. It takes `Rgb8` and `Rgba8` pixels,
+/// writing a header with the matching channel count unless the options force
+/// one.
+pub struct QoiEncoder<W: Write> {
+    sink: W,
+    options: EncodeOptions,
+}
+
+impl<W: Write> QoiEncoder<W> {
+    pub fn new(sink: W) -> QoiEncoder<W> {
+        QoiEncoder::with_options(sink, EncodeOptions::default())
+    }
+
+    pub fn with_options(sink: W, options: EncodeOptions) -> QoiEncoder<W> {
+        QoiEncoder { sink, options }
+    }
+}
+
+impl<W: Write> ImageEncoder for QoiEncoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> image::ImageResult<()> {
+        let input = match color_type {
+            ExtendedColorType::Rgb8 => Channels::RGB,
+            ExtendedColorType::Rgba8 => Channels::RGBA,
+            _ => {
+                return Err(ImageError::Unsupported(
+                    UnsupportedError::from_format_and_kind(
+                        FORMAT,
+                        UnsupportedErrorKind::Color(color_type),
+                    ),
+                ))
+            }
+        };
+
+        let input_header = Header {
+            width,
+            height,
+            channels: input,
+            color_space: self.options.color_space,
+        };
+        // the input is checked against its own layout; the header may then say otherwise
+        crate::check_input_len(buf, &input_header).map_err(encoding_error)?;
+        let header = Header {
+            channels: self.options.channels.unwrap_or(input),
+            ..input_header
+        };
+        self.options.limits.check(&header).map_err(encoding_error)?;
+
+        let pixels = packed_pixels(buf, input).map(|px| match header.channels {
+            Channels::RGB => crate::PixelRGBA { a: 255, ..px },
+            Channels::RGBA => px,
+        });
+        let image = QOIImage::from_parts(header, encode_chunks(pixels, self.options.mode));
+        self.sink
+            .write_all(&image.serialize())
+            .map_err(ImageError::IoError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader};
+
+    use image::{DynamicImage, ImageReader};
+
+    use super::*;
+    use crate::{decode_to_vec, ColorSpace};
+
+    #[test]
+    fn dice_as_dynamic_image() {
+        let decoder =
+            QoiDecoder::new(BufReader::new(File::open("files/dice.qoi").unwrap())).unwrap();
+        assert_eq!(decoder.dimensions(), (800, 600));
+        assert_eq!(decoder.color_type(), ColorType::Rgba8);
+
+        let img = DynamicImage::from_decoder(decoder).unwrap();
+        assert!(img.to_rgba8().into_raw() == std::fs::read("files/dice2.rgba").unwrap());
+    }
+
+    #[test]
+    fn dynamic_image_round_trip() {
+        let testcard = std::fs::read("files/testcard_rgba.rgba").unwrap();
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_raw(256, 256, testcard).unwrap());
+
+        let mut out = Vec::new();
+        img.write_with_encoder(QoiEncoder::new(&mut out)).unwrap();
+        let (header, pixels) = decode_to_vec(&out).unwrap();
+        assert_eq!(header.channels, Channels::RGBA);
+        assert!(pixels == img.to_rgba8().into_raw());
+
+        let rgb = DynamicImage::ImageRgb8(img.to_rgb8());
+        let options = EncodeOptions {
+            color_space: ColorSpace::SRGB,
+            ..EncodeOptions::default()
+        };
+        let mut out = Vec::new();
+        rgb.write_with_encoder(QoiEncoder::with_options(&mut out, options))
+            .unwrap();
+        let decoded = ImageReader::new(std::io::Cursor::new(&out))
+            .with_guessed_format()
+            .unwrap();
+        assert_eq!(decoded.format(), Some(ImageFormat::Qoi));
+        let decoded = DynamicImage::from_decoder(QoiDecoder::new(out.as_slice()).unwrap()).unwrap();
+        assert_eq!(decoded, rgb);
+
+        let luma = DynamicImage::ImageLuma8(img.to_luma8());
+        assert!(matches!(
+            luma.write_with_encoder(QoiEncoder::new(Vec::new())),
+            Err(ImageError::Unsupported(_))
+        ));
+    }
+
+    /// Fails every read, so any attempt to read past the header shows.
+    struct Bottomless;
+
+    impl Read for Bottomless {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("read past the header"))
+        }
+    }
+
+    #[test]
+    fn limits_are_checked_before_the_body_is_read() {
+        let header = Header {
+            width: 100_000,
+            height: 100_000,
+            channels: Channels::RGBA,
+            color_space: ColorSpace::SRGB,
+        };
+        let bytes = header.to_bytes();
+        let source = bytes.as_slice().chain(Bottomless);
+        assert!(matches!(
+            QoiDecoder::new(source),
+            Err(ImageError::Limits(_))
+        ));
+
+        let small = Header {
+            width: 1,
+            height: 1,
+            ..header
+        };
+        let bytes = small.to_bytes();
+        let source = bytes.as_slice().chain(Bottomless);
+        assert!(matches!(
+            QoiDecoder::new(source),
+            Err(ImageError::IoError(_))
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+//! Read-only views of the chunks an image is made of, for working out why it
+//! compresses the way it does.
+
+use crate::{Chunk, DecodeState, PixelRGBA, QOIImage};
+
+/// The kinds of chunk, one per opcode.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    RGB,
+    RGBA,
+    Index,
+    Diff,
+    Luma,
+    Run,
+}
+
+impl Opcode {
+    /// Every opcode, in the order [`ChunkStats`] reports them.
+    pub const ALL: [Opcode; 6] = [
+        Opcode::RGB,
+        Opcode::RGBA,
+        Opcode::Index,
+        Opcode::Diff,
+        Opcode::Luma,
+        Opcode::Run,
+    ];
+}
+
+/// A chunk, where it sits in the stream and the pixels it produces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub chunk: Chunk,
+    /// Byte offset of the chunk from the start of the stream, header included.
+    pub offset: usize,
+    /// The position, counting row by row, of the first pixel the chunk
+    /// produces.
+    pub pixel_index: u64,
+    /// How many pixels the chunk produces: the run length for a run, one for
+    /// anything else, and none once the image is full.
+    pub pixel_count: u64,
+    /// The pixel the chunk produces.
+    pub pixel: PixelRGBA,
+}
+
+/// An iterator over the chunks of a [`QOIImage`], made by
+/// [`QOIImage::chunks`].
+pub struct Chunks<'a> {
+    chunks: std::slice::Iter<'a, Chunk>,
+    state: DecodeState,
+    offset: usize,
+    pixel_index: u64,
+    pixel_total: u64,
+}
+
+impl Iterator for Chunks<'_> {
+    type Item = ChunkInfo;
+
+    fn next(&mut self) -> Option<ChunkInfo> {
+        let chunk = self.chunks.next()?;
+        let (pixel, n) = self.state.apply(chunk);
+        let pixel_count = (n as u64).min(self.pixel_total - self.pixel_index);
+        let info = ChunkInfo {
+            chunk: chunk.clone(),
+            offset: self.offset,
+            pixel_index: self.pixel_index,
+            pixel_count,
+            pixel,
+        };
+        self.offset += chunk.encoded_len();
+        self.pixel_index += pixel_count;
+        Some(info)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Chunks<'_> {}
+
+impl QOIImage {
+    /// Walks the image's chunks in stream order, end marker excluded.
+    pub fn chunks(&self) -> Chunks<'_> {
+        Chunks {
+            chunks: self.data.iter(),
+            state: DecodeState::new(),
+            offset: 14,
+            pixel_index: 0,
+            pixel_total: self.header().pixel_count(),
+        }
+    }
+
+    /// Tallies the image's chunks by opcode.
+    pub fn chunk_stats(&self) -> ChunkStats {
+        self.chunks().collect()
+    }
+}
+
+/// Totals for one kind of chunk.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpcodeStats {
+    pub count: u64,
+    /// Bytes taken by the chunks, tags included.
+    pub bytes: u64,
+    /// Pixels the chunks produce.
+    pub pixels: u64,
+}
+
+impl OpcodeStats {
+    fn add(&mut self, other: OpcodeStats) {
+        self.count += other.count;
+        self.bytes += other.bytes;
+        self.pixels += other.pixels;
+    }
+}
+
+/// A summary of the chunks of an image, by opcode. Collect one from
+/// [`ChunkInfo`]s, or get it from [`QOIImage::chunk_stats`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkStats {
+    by_opcode: [OpcodeStats; 6],
+}
+
+impl ChunkStats {
+    /// Counts one more chunk.
+    pub fn add(&mut self, info: &ChunkInfo) {
+        self.by_opcode[info.chunk.opcode() as usize].add(OpcodeStats {
+            count: 1,
+            bytes: info.chunk.encoded_len() as u64,
+            pixels: info.pixel_count,
+        });
+    }
+
+    /// The totals for chunks of one kind.
+    pub fn opcode(&self, opcode: Opcode) -> OpcodeStats {
+        self.by_opcode[opcode as usize]
+    }
+
+    /// The totals over every chunk.
+    pub fn total(&self) -> OpcodeStats {
+        let mut total = OpcodeStats::default();
+        self.by_opcode.iter().for_each(|&stats| total.add(stats));
+        total
+    }
+
+    /// The fraction of chunk bytes taken by chunks of one kind. The header
+    /// and end marker are not counted.
+    pub fn byte_share(&self, opcode: Opcode) -> f64 {
+        ratio(self.opcode(opcode).bytes, self.total().bytes)
+    }
+
+    /// The mean number of pixels in a run chunk.
+    pub fn average_run_length(&self) -> f64 {
+        let run = self.opcode(Opcode::Run);
+        ratio(run.pixels, run.count)
+    }
+
+    /// The fraction of pixels outside runs that were found in the index of
+    /// recent pixels.
+    pub fn index_hit_rate(&self) -> f64 {
+        let non_run = self.total().count - self.opcode(Opcode::Run).count;
+        ratio(self.opcode(Opcode::Index).count, non_run)
+    }
+}
+
+impl FromIterator<ChunkInfo> for ChunkStats {
+    fn from_iter<I: IntoIterator<Item = ChunkInfo>>(iter: I) -> Self {
+        let mut stats = ChunkStats::default();
+        iter.into_iter().for_each(|info| stats.add(&info));
+        stats
+    }
+}
+
+/// `num / den`, or zero when there is nothing to divide.
+fn ratio(num: u64, den: u64) -> f64 {
+    if den == 0 {
+        0.0
+    } else {
+        num as f64 / den as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Channels, ColorSpace, Header, Index, PixelRGB, Run};
+
+    fn image(data: &[u8]) -> QOIImage {
+        QOIImage::from_reader(data).unwrap()
+    }
+
+    #[test]
+    fn chunk_offsets_and_pixels() {
+        // 5x1: an RGB pixel, a luma step, a run of two and an index hit
+        let header = Header {
+            width: 5,
+            height: 1,
+            channels: Channels::RGB,
+            color_space: ColorSpace::SRGB,
+        };
+        let mut data = header.to_bytes().to_vec();
+        data.extend_from_slice(&[0xfe, 10, 20, 30, 0x80 | 35, 0x88, 0xc1]);
+        let first = PixelRGBA::new(10, 20, 30, 255);
+        data.push(first.hash_index() as u8);
+        data.extend_from_slice(&crate::QOI_END_MARKER);
+
+        let infos: Vec<ChunkInfo> = image(&data).chunks().collect();
+        let summary: Vec<(Opcode, usize, u64, u64)> = infos
+            .iter()
+            .map(|i| (i.chunk.opcode(), i.offset, i.pixel_index, i.pixel_count))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Opcode::RGB, 14, 0, 1),
+                (Opcode::Luma, 18, 1, 1),
+                (Opcode::Run, 20, 2, 2),
+                (Opcode::Index, 21, 4, 1),
+            ]
+        );
+        assert_eq!(infos[0].chunk, Chunk::RGB(PixelRGB::new(10, 20, 30)));
+        assert_eq!(infos[1].pixel, PixelRGBA::new(13, 23, 33, 255));
+        assert_eq!(infos[3].pixel, first);
+        match &infos[1].chunk {
+            Chunk::Luma(luma) => assert_eq!((luma.dg(), luma.dr_dg(), luma.db_dg()), (3, 0, 0)),
+            other => panic!("expected a luma chunk, got {other:?}"),
+        }
+        assert_eq!(infos[2].chunk, Chunk::Run(Run::new(2).unwrap()));
+        assert_eq!(
+            infos[3].chunk,
+            Chunk::Index(Index::new(first.hash_index() as u8).unwrap())
+        );
+        assert_eq!(
+            (Run::new(0), Run::new(63), Index::new(64)),
+            (None, None, None)
+        );
+
+        let stats = image(&data).chunk_stats();
+        assert_eq!(
+            stats.total(),
+            OpcodeStats {
+                count: 4,
+                bytes: 8,
+                pixels: 5
+            }
+        );
+        assert_eq!(stats.byte_share(Opcode::RGB), 0.5);
+        assert_eq!(stats.average_run_length(), 2.0);
+        assert_eq!(stats.index_hit_rate(), 1.0 / 3.0);
+        assert_eq!(stats.opcode(Opcode::Diff), OpcodeStats::default());
+    }
+
+    #[test]
+    fn dice_stats() {
+        let data = std::fs::read("files/dice.qoi").unwrap();
+        let image = image(&data);
+        let stats = image.chunk_stats();
+        let total = stats.total();
+        assert_eq!(total.count, image.chunk_count() as u64);
+        assert_eq!(total.bytes, data.len() as u64 - 14 - 8);
+        assert_eq!(total.pixels, 800 * 600);
+        let shares: f64 = Opcode::ALL.iter().map(|&op| stats.byte_share(op)).sum();
+        assert!((shares - 1.0).abs() < 1e-9);
+
+        let last = image.chunks().last().unwrap();
+        assert_eq!(last.offset + last.chunk.encoded_len(), data.len() - 8);
+        assert_eq!(last.pixel_index + last.pixel_count, 800 * 600);
+    }
+}
@@ -1,52 +1,477 @@
-enum ColorSpace {
+use std::fmt;
+
+pub mod asm;
+pub mod bmp;
+#[cfg(feature = "image")]
+mod image_io;
+mod inspect;
+pub mod png;
+pub mod pnm;
+mod stream;
+pub mod tga;
+
+#[cfg(feature = "image")]
+pub use image_io::{QoiDecoder, QoiEncoder};
+pub use inspect::{ChunkInfo, ChunkStats, Chunks, Opcode, OpcodeStats};
+pub use stream::{StreamDecoder, StreamEncoder};
+
+const QOI_MAGIC: [u8; 4] = *b"qoif";
+const QOI_END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+/// Everything that can go wrong while reading a QOI stream.
+///
+/// Offsets are byte positions from the start of the stream, header included.
+#[derive(Debug)]
+pub enum QoiError {
+    /// The stream ended after `len` bytes, before the 14 byte header was complete.
+    TruncatedHeader { len: usize },
+    /// The first four bytes were not `qoif`.
+    BadMagic { found: [u8; 4] },
+    /// The channels byte (offset 12) was neither 3 nor 4.
+    BadChannels { value: u8 },
+    /// The colorspace byte (offset 13) was neither 0 nor 1.
+    BadColorSpace { value: u8 },
+    /// The stream ended in the middle of the chunk starting at `offset`.
+    TruncatedChunk { offset: usize },
+    /// The 8 byte end marker was expected at `offset` but not found there.
+    MissingEndMarker { offset: usize },
+    /// The run chunk at `offset` repeats a pixel past the end of the image.
+    RunOverflow { offset: usize },
+    /// Bytes follow the end marker, starting at `offset`.
+    TrailingData { offset: usize },
+    /// The chunks describe a different number of pixels than the header.
+    PixelCountMismatch { expected: u64, actual: u64 },
+    /// A pixel buffer handed to an encoder does not hold `width * height`
+    /// pixels of the header's channel count.
+    BadInputLength { expected: u64, actual: u64 },
+    /// Pixels handed to a [`StreamEncoder`] are `len` bytes, which is not a
+    /// whole number of `channels` byte pixels. Bytes of a pixel left
+    /// unfinished by an earlier [`std::io::Write::write`] count towards `len`.
+    UnalignedInput { len: u64, channels: usize },
+    /// An output [`Layout`]'s stride is shorter than a row of pixels.
+    BadStride { stride: usize, row_len: usize },
+    /// An output buffer is too small for the image.
+    OutputTooSmall { required: u64, actual: u64 },
+    /// The image is bigger than the [`Limits`] allow.
+    LimitExceeded {
+        kind: LimitKind,
+        value: u64,
+        max: u64,
+    },
+    /// The underlying reader failed.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for QoiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QoiError::TruncatedHeader { len } => {
+                write!(f, "Malformed input: incomplete header ({len} of 14 bytes)")
+            }
+            QoiError::BadMagic { found } => {
+                write!(
+                    f,
+                    "Malformed input: magic bytes not found (got {found:02x?})"
+                )
+            }
+            QoiError::BadChannels { value } => {
+                write!(
+                    f,
+                    "Malformed input: invalid channels value {value} at byte 12"
+                )
+            }
+            QoiError::BadColorSpace { value } => {
+                write!(
+                    f,
+                    "Malformed input: invalid colorspace value {value} at byte 13"
+                )
+            }
+            QoiError::TruncatedChunk { offset } => write!(
+                f,
+                "Malformed input: reached end of file abruptly in chunk at byte {offset}"
+            ),
+            QoiError::MissingEndMarker { offset } => {
+                write!(f, "Malformed input: no end marker at byte {offset}")
+            }
+            QoiError::RunOverflow { offset } => write!(
+                f,
+                "Malformed input: run at byte {offset} goes past the end of the image"
+            ),
+            QoiError::TrailingData { offset } => write!(
+                f,
+                "Malformed input: unexpected data after the end marker at byte {offset}"
+            ),
+            QoiError::PixelCountMismatch { expected, actual } => write!(
+                f,
+                "Malformed input: expected {expected} pixels but chunks describe {actual}"
+            ),
+            QoiError::BadInputLength { expected, actual } => write!(
+                f,
+                "Invalid input: expected {expected} bytes of pixel data but got {actual}"
+            ),
+            QoiError::UnalignedInput { len, channels } => write!(
+                f,
+                "Invalid input: {len} bytes of pixel data are not whole {channels} byte pixels"
+            ),
+            QoiError::BadStride { stride, row_len } => write!(
+                f,
+                "Invalid layout: stride of {stride} bytes is shorter than a {row_len} byte row"
+            ),
+            QoiError::OutputTooSmall { required, actual } => write!(
+                f,
+                "Invalid output: buffer holds {actual} bytes but the image needs {required}"
+            ),
+            QoiError::LimitExceeded { kind, value, max } => {
+                let what = match kind {
+                    LimitKind::Width => "width",
+                    LimitKind::Height => "height",
+                    LimitKind::Pixels => "pixel count",
+                    LimitKind::Bytes => "size in bytes",
+                };
+                write!(
+                    f,
+                    "Image too large: {what} of {value} exceeds the limit of {max}"
+                )
+            }
+            QoiError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl QoiError {
+    /// The byte offset the error points at, if it has one.
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            QoiError::TruncatedHeader { len } => Some(len),
+            QoiError::BadMagic { .. } => Some(0),
+            QoiError::BadChannels { .. } => Some(12),
+            QoiError::BadColorSpace { .. } => Some(13),
+            QoiError::TruncatedChunk { offset }
+            | QoiError::MissingEndMarker { offset }
+            | QoiError::RunOverflow { offset }
+            | QoiError::TrailingData { offset } => Some(offset),
+            QoiError::PixelCountMismatch { .. }
+            | QoiError::BadInputLength { .. }
+            | QoiError::UnalignedInput { .. }
+            | QoiError::BadStride { .. }
+            | QoiError::OutputTooSmall { .. }
+            | QoiError::LimitExceeded { .. }
+            | QoiError::Io(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for QoiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QoiError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for QoiError {
+    fn from(e: std::io::Error) -> Self {
+        QoiError::Io(e)
+    }
+}
+
+/// The colorspace byte of a QOI header. It is purely informative and does not
+/// change how pixels are encoded.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// sRGB with linear alpha.
     SRGB,
+    /// All channels linear.
     Linear,
 }
 
-enum Channels {
+/// The channels byte of a QOI header.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channels {
     RGB,
     RGBA,
 }
 
-#[derive(Clone)]
-enum Chunk {
-    RGB(PixelRGB),
-    RGBA(PixelRGBA),
-    Index(u8),
-    Diff(DiffRGB),
-    Luma(Luma),
-    Run(u8),
+impl Channels {
+    /// The number of bytes a pixel takes up in a packed buffer.
+    pub fn count(self) -> usize {
+        match self {
+            Channels::RGB => 3,
+            Channels::RGBA => 4,
+        }
+    }
 }
 
-#[derive(Clone)]
-struct Luma(u8, u8, u8);
+/// Controls the header an encoder writes, and how it picks chunks.
+///
+/// The default is the "auto" mode: RGBA if any pixel is not fully opaque and
+/// RGB otherwise, tagged as linear.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// Forces the channel count instead of inferring it from the pixels.
+    /// Forcing RGB discards alpha, encoding every pixel as opaque.
+    pub channels: Option<Channels>,
+    pub color_space: ColorSpace,
+    /// The limits the image dimensions are checked against. None are applied
+    /// by default.
+    pub limits: Limits,
+    pub mode: EncodeMode,
+}
 
-#[derive(Clone)]
-struct DiffRGB(u8, u8, u8);
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            channels: None,
+            color_space: ColorSpace::Linear,
+            limits: Limits::none(),
+            mode: EncodeMode::default(),
+        }
+    }
+}
 
-pub struct QOIImage {
-    width: u32,
-    height: u32,
-    channels: Channels,
-    color_space: ColorSpace,
-    data: Vec<Chunk>,
+/// Which of the valid encodings of an image an encoder writes. Every mode
+/// decodes to the same pixels.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EncodeMode {
+    /// Colour differences are taken as plain integers, so a step from 255 to
+    /// 0 is a full literal rather than a difference of one.
+    #[default]
+    Standard,
+    /// Exactly the bytes the reference `qoi.h` encoder writes. Differences are
+    /// taken modulo 256, as signed bytes, so steps across the ends of a
+    /// channel's range can still be stored as diff or luma chunks.
+    ReferenceCompatible,
 }
 
-impl QOIImage {
-    pub fn from_qoi_file<R: std::io::Read>(
-        mut source: std::io::Bytes<R>,
-    ) -> Result<QOIImage, &'static str> {
-        let mut header = [0u8; 14];
-        for i in 0..14 {
-            if let Some(Ok(x)) = source.next() {
-                header[i] = x;
-            } else {
-                return Err("Malformed input: incomplete header");
+/// Controls how forgiving a decoder is.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Follow the spec to the letter: the chunks must describe exactly
+    /// `width * height` pixels, runs may not go past the last pixel, and the
+    /// end marker must be the last 8 bytes of the stream.
+    ///
+    /// Without it, runs may go past the last pixel and further chunks may
+    /// follow it, up to the first end marker after the last pixel; their
+    /// pixels are dropped. Anything after that end marker is ignored.
+    pub strict: bool,
+    pub limits: Limits,
+}
+
+/// Caps on the image size a header may claim, checked before anything is
+/// allocated for the image.
+///
+/// The default allows up to 400 million pixels, as the reference
+/// implementation does, and at most a million along either side, as libpng
+/// does. The side caps matter for images with no pixels at all: a header
+/// can claim a width of zero and a height of four billion.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// The most pixels, `width * height`.
+    pub max_pixels: u64,
+    /// The largest image as packed pixels, `width * height * channels`.
+    pub max_bytes: u64,
+}
+
+impl Limits {
+    /// No limits at all.
+    pub const fn none() -> Limits {
+        Limits {
+            max_width: u32::MAX,
+            max_height: u32::MAX,
+            max_pixels: u64::MAX,
+            max_bytes: u64::MAX,
+        }
+    }
+
+    pub fn check(&self, header: &Header) -> Result<(), QoiError> {
+        let checks = [
+            (LimitKind::Width, header.width as u64, self.max_width as u64),
+            (
+                LimitKind::Height,
+                header.height as u64,
+                self.max_height as u64,
+            ),
+            (LimitKind::Pixels, header.pixel_count(), self.max_pixels),
+            (
+                LimitKind::Bytes,
+                header
+                    .pixel_count()
+                    .saturating_mul(header.channels.count() as u64),
+                self.max_bytes,
+            ),
+        ];
+        for (kind, value, max) in checks {
+            if value > max {
+                return Err(QoiError::LimitExceeded { kind, value, max });
             }
         }
+        Ok(())
+    }
+}
 
-        if Ok("qoif".to_string()) != String::from_utf8(header[0..4].to_vec()) {
-            return Err("Malformed input: magic bytes not found");
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 1_000_000,
+            max_height: 1_000_000,
+            max_pixels: 400_000_000,
+            ..Limits::none()
+        }
+    }
+}
+
+/// Which of the [`Limits`] an image broke.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LimitKind {
+    Width,
+    Height,
+    Pixels,
+    Bytes,
+}
+
+/// The byte order of a pixel in an output buffer. The three byte orders drop
+/// alpha.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChannelOrder {
+    RGBA,
+    BGRA,
+    ARGB,
+    RGB,
+    BGR,
+}
+
+impl ChannelOrder {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ChannelOrder::RGBA | ChannelOrder::BGRA | ChannelOrder::ARGB => 4,
+            ChannelOrder::RGB | ChannelOrder::BGR => 3,
+        }
+    }
+
+    fn write(self, px: PixelRGBA, out: &mut [u8]) {
+        let PixelRGBA { r, g, b, a } = px;
+        match self {
+            ChannelOrder::RGBA => out.copy_from_slice(&[r, g, b, a]),
+            ChannelOrder::BGRA => out.copy_from_slice(&[b, g, r, a]),
+            ChannelOrder::ARGB => out.copy_from_slice(&[a, r, g, b]),
+            ChannelOrder::RGB => out.copy_from_slice(&[r, g, b]),
+            ChannelOrder::BGR => out.copy_from_slice(&[b, g, r]),
+        }
+    }
+}
+
+/// Where decoded pixels go in a caller's buffer: row `y` starts at byte
+/// `y * stride`, and pixels within a row are packed in `order`. Bytes between
+/// the end of a row and the next stride are left alone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub order: ChannelOrder,
+    /// Bytes from the start of one row to the start of the next.
+    pub stride: usize,
+}
+
+impl Layout {
+    /// Rows packed back to back with no padding.
+    pub fn packed(order: ChannelOrder, width: u32) -> Layout {
+        Layout {
+            order,
+            stride: width as usize * order.bytes_per_pixel(),
+        }
+    }
+
+    /// The smallest buffer that holds an image of this size. The last row
+    /// does not need padding out to the stride.
+    pub fn required_len(&self, width: u32, height: u32) -> u64 {
+        let row_len = width as u64 * self.order.bytes_per_pixel() as u64;
+        match height {
+            0 => 0,
+            _ => (height as u64 - 1) * self.stride as u64 + row_len,
+        }
+    }
+
+    fn writer(self, buf: &mut [u8], width: u32, height: u32) -> Result<LayoutWriter<'_>, QoiError> {
+        let row_len = width as usize * self.order.bytes_per_pixel();
+        if self.stride < row_len {
+            return Err(QoiError::BadStride {
+                stride: self.stride,
+                row_len,
+            });
+        }
+        let required = self.required_len(width, height);
+        if (buf.len() as u64) < required {
+            return Err(QoiError::OutputTooSmall {
+                required,
+                actual: buf.len() as u64,
+            });
+        }
+        Ok(LayoutWriter {
+            buf,
+            layout: self,
+            row_len,
+            row_start: 0,
+            pos: 0,
+        })
+    }
+}
+
+/// Writes pixels one after another into a buffer already checked to be big
+/// enough for the image.
+struct LayoutWriter<'a> {
+    buf: &'a mut [u8],
+    layout: Layout,
+    row_len: usize,
+    row_start: usize,
+    pos: usize,
+}
+
+impl LayoutWriter<'_> {
+    fn push(&mut self, px: PixelRGBA) {
+        let bpp = self.layout.order.bytes_per_pixel();
+        self.layout
+            .order
+            .write(px, &mut self.buf[self.pos..self.pos + bpp]);
+        self.pos += bpp;
+        if self.pos - self.row_start == self.row_len {
+            self.row_start += self.layout.stride;
+            self.pos = self.row_start;
+        }
+    }
+}
+
+/// What a recovering decoder salvaged from a damaged stream.
+#[derive(Debug)]
+pub struct RecoveryReport {
+    /// Why decoding stopped early, or `None` if the stream was intact.
+    /// [`QoiError::offset`] gives the byte where the stream broke.
+    pub error: Option<QoiError>,
+    /// Pixels decoded from the stream.
+    pub pixels_recovered: u64,
+    /// Pixels at the end of the image set to the fill colour.
+    pub pixels_filled: u64,
+}
+
+/// The 14 byte header at the start of every QOI file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub channels: Channels,
+    pub color_space: ColorSpace,
+}
+
+impl Header {
+    /// Parses and validates a raw header.
+    pub fn from_bytes(header: &[u8; 14]) -> Result<Header, QoiError> {
+        if header[0..4] != QOI_MAGIC {
+            return Err(QoiError::BadMagic {
+                found: header[0..4].try_into().unwrap(),
+            });
         }
 
         let width = u32::from_be_bytes(header[4..8].try_into().unwrap());
@@ -54,476 +479,1443 @@ impl QOIImage {
         let channels = match header[12] {
             3u8 => Channels::RGB,
             4u8 => Channels::RGBA,
-            _ => {
-                return Err("Malformed input: invalid channels data");
+            n => {
+                return Err(QoiError::BadChannels { value: n });
             }
         };
         let color_space = match header[13] {
             0u8 => ColorSpace::SRGB,
             1u8 => ColorSpace::Linear,
-            _ => {
-                return Err("Malformed input: invalid channels data");
+            n => {
+                return Err(QoiError::BadColorSpace { value: n });
             }
         };
 
-        let mut data: Vec<Chunk> = Vec::new();
-
-        // build the chunks
-        let mut zeroes_so_far = 0;
-        while let Some(Ok(current_chunk)) = source.next() {
-            match current_chunk {
-                0b11111111 => {
-                    let r = {
-                        let r = source.next();
-                        if r.is_none() {
-                            return Err("Malformed input: reached end of file abruptly");
-                        } else {
-                            let r = r.unwrap();
-                            if r.is_err() {
-                                return Err("Malformed input: reached end of file abruptly");
-                            } else {
-                                r.unwrap()
-                            }
-                        }
-                    };
-
-                    let g = {
-                        let g = source.next();
-                        if g.is_none() {
-                            return Err("Malformed input: reached end of file abruptly");
-                        } else {
-                            let g = g.unwrap();
-                            if g.is_err() {
-                                return Err("Malformed input: reached end of file abruptly");
-                            } else {
-                                g.unwrap()
-                            }
-                        }
-                    };
-
-                    let b = {
-                        let b = source.next();
-                        if b.is_none() {
-                            return Err("Malformed input: reached end of file abruptly");
-                        } else {
-                            let b = b.unwrap();
-                            if b.is_err() {
-                                return Err("Malformed input: reached end of file abruptly");
-                            } else {
-                                b.unwrap()
-                            }
-                        }
-                    };
-
-                    let a = {
-                        let a = source.next();
-                        if a.is_none() {
-                            return Err("Malformed input: reached end of file abruptly");
-                        } else {
-                            let a = a.unwrap();
-                            if a.is_err() {
-                                return Err("Malformed input: reached end of file abruptly");
-                            } else {
-                                a.unwrap()
-                            }
-                        }
-                    };
-
-                    data.push(Chunk::RGBA(PixelRGBA(r, g, b, a)));
-                    zeroes_so_far = 0;
-                }
-                0b11111110 => {
-                    let r = {
-                        let r = source.next();
-                        if r.is_none() {
-                            return Err("Malformed input: reached end of file abruptly");
-                        } else {
-                            let r = r.unwrap();
-                            if r.is_err() {
-                                return Err("Malformed input: reached end of file abruptly");
-                            } else {
-                                r.unwrap()
-                            }
-                        }
-                    };
-
-                    let g = {
-                        let g = source.next();
-                        if g.is_none() {
-                            return Err("Malformed input: reached end of file abruptly");
-                        } else {
-                            let g = g.unwrap();
-                            if g.is_err() {
-                                return Err("Malformed input: reached end of file abruptly");
-                            } else {
-                                g.unwrap()
-                            }
-                        }
-                    };
-
-                    let b = {
-                        let b = source.next();
-                        if b.is_none() {
-                            return Err("Malformed input: reached end of file abruptly");
-                        } else {
-                            let b = b.unwrap();
-                            if b.is_err() {
-                                return Err("Malformed input: reached end of file abruptly");
-                            } else {
-                                b.unwrap()
-                            }
-                        }
-                    };
-
-                    data.push(Chunk::RGB(PixelRGB(r, g, b)));
-                    zeroes_so_far = 0;
-                }
-                n if n >> 6 == 0b11 => {
-                    data.push(Chunk::Run(n & 0b00111111));
-                    zeroes_so_far = 0;
-                }
-                n if n >> 6 == 0b00 => {
-                    if n == 0 {
-                        zeroes_so_far += 1;
-                    }
-                    if n == 1 && zeroes_so_far == 7 {
-                        for _ in 0..7 {
-                            data.pop();
-                        }
-                        break;
-                    }
-                    data.push(Chunk::Index(n & 0b00111111));
-                }
-                n if n >> 6 == 0b01 => {
-                    let r: u8 = (n & 0b00110000) >> 4;
-                    let g: u8 = (n & 0b00001100) >> 2;
-                    let b: u8 = n & 0b11;
-                    data.push(Chunk::Diff(DiffRGB(r, g, b)));
-                    zeroes_so_far = 0;
-                }
-                n if n >> 6 == 0b10 => {
-                    let dg = n & 0b00111111;
-                    if let Some(Ok(next_byte)) = source.next() {
-                        // dg = 6 bit green channel difference from the previous pixel -32..31
-                        // next_byte = ((current_pixel.r - prev_pixel.r) - (current_pixel.g - prev_pixel.g) << 4)
-                        //           + ((current_pixel.b - prev_pixel.b) - (current_pixel.g - prev_pixel.g)
-                        let dr_dg = next_byte >> 4;
-                        let db_dg = next_byte & 0b1111;
-                        data.push(Chunk::Luma(Luma(dg, dr_dg, db_dg)));
-                        zeroes_so_far = 0;
-                    } else {
-                        return Err("Malformed input: reached end of file abruptly");
-                    }
-                }
-                _ => return Err("Malformed input: invalid channels data"),
-            }
-        }
-
-        Ok(QOIImage {
+        Ok(Header {
             width,
             height,
             channels,
             color_space,
-            data,
         })
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        // build header
-        let mut header: Vec<u8> = Vec::with_capacity(14);
-        header.extend_from_slice(&vec![0x71, 0x6F, 0x69, 0x66]);
-        header.extend_from_slice(&self.width.to_be_bytes());
-        header.extend_from_slice(&self.height.to_be_bytes());
-        let channels: u8 = match self.channels {
-            Channels::RGB => 3,
-            Channels::RGBA => 4,
-        };
-        header.push(channels);
-        let color_space: u8 = match self.color_space {
-            ColorSpace::Linear => 1,
+    pub fn to_bytes(&self) -> [u8; 14] {
+        let mut header = [0u8; 14];
+        header[0..4].copy_from_slice(&QOI_MAGIC);
+        header[4..8].copy_from_slice(&self.width.to_be_bytes());
+        header[8..12].copy_from_slice(&self.height.to_be_bytes());
+        header[12] = self.channels.count() as u8;
+        header[13] = match self.color_space {
             ColorSpace::SRGB => 0,
+            ColorSpace::Linear => 1,
         };
-        header.push(color_space);
+        header
+    }
 
-        // build image data
-        let image = self
-            .data
-            .iter()
-            .fold(Vec::new(), |mut data, chunk| -> Vec<u8> {
-                match chunk {
-                    &Chunk::RGB(PixelRGB(r, g, b)) => {
-                        data.extend_from_slice(&[0b11111110, r, g, b]);
-                        data
-                    }
-                    &Chunk::RGBA(PixelRGBA(r, g, b, a)) => {
-                        data.extend_from_slice(&[0b11111111, r, g, b, a]);
-                        data
-                    }
-                    &Chunk::Index(i) => {
-                        data.push(i);
-                        data
-                    }
-                    &Chunk::Diff(DiffRGB(r, g, b)) => {
-                        data.push((0b01 << 6) + (r << 4) + (g << 2) + b);
-                        data
-                    }
-                    &Chunk::Luma(Luma(dg, dr_dg, db_dg)) => {
-                        data.push((0b10 << 6) + dg);
-                        data.push((dr_dg << 4) + db_dg);
-                        data
-                    }
-                    &Chunk::Run(n) => {
-                        data.push((0b11 << 6) + n);
-                        data
-                    }
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_bytes());
+    }
+
+    /// The number of pixels the image holds.
+    pub fn pixel_count(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+
+/// One chunk of a QOI stream.
+///
+/// The payloads that have a range can only be made through their checked
+/// constructors, so every chunk encodes to valid bytes.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Chunk {
+    /// A new color, keeping the previous pixel's alpha.
+    RGB(PixelRGB),
+    /// A new color and alpha.
+    RGBA(PixelRGBA),
+    /// A pixel from the index of recent pixels.
+    Index(Index),
+    /// A small change from the previous pixel.
+    Diff(DiffRGB),
+    /// A larger change from the previous pixel, mostly in green.
+    Luma(Luma),
+    /// The previous pixel, repeated.
+    Run(Run),
+}
+
+/// The payload of an index chunk: a position in the index of recent pixels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Index(u8);
+
+impl Index {
+    /// An index payload, or `None` if `position` is not in `0..64`.
+    pub fn new(position: u8) -> Option<Index> {
+        (position < 64).then_some(Index(position))
+    }
+
+    /// The position in the index, `0..64`.
+    pub fn position(&self) -> u8 {
+        self.0
+    }
+}
+
+/// The payload of a run chunk. The length is stored biased, as it is
+/// encoded; the accessor gives the number of pixels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Run(u8);
+
+impl Run {
+    /// A run payload, or `None` if `pixel_count` is not in `1..=62`.
+    pub fn new(pixel_count: u8) -> Option<Run> {
+        (1..=62)
+            .contains(&pixel_count)
+            .then(|| Run(pixel_count - 1))
+    }
+
+    /// The number of pixels the run repeats, `1..=62`.
+    pub fn pixel_count(&self) -> u8 {
+        self.0 + 1
+    }
+}
+
+/// The payload of a luma chunk. Values are stored biased, as they are
+/// encoded; the accessors give the signed differences.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Luma(u8, u8, u8);
+
+impl Luma {
+    /// A luma payload from signed differences, or `None` if any is out of
+    /// range.
+    pub fn new(dg: i8, dr_dg: i8, db_dg: i8) -> Option<Luma> {
+        let fits =
+            (-32..=31).contains(&dg) && (-8..=7).contains(&dr_dg) && (-8..=7).contains(&db_dg);
+        fits.then(|| Luma((dg + 32) as u8, (dr_dg + 8) as u8, (db_dg + 8) as u8))
+    }
+
+    /// The green difference from the previous pixel, `-32..=31`.
+    pub fn dg(&self) -> i8 {
+        self.0 as i8 - 32
+    }
+
+    /// The red difference minus the green difference, `-8..=7`.
+    pub fn dr_dg(&self) -> i8 {
+        self.1 as i8 - 8
+    }
+
+    /// The blue difference minus the green difference, `-8..=7`.
+    pub fn db_dg(&self) -> i8 {
+        self.2 as i8 - 8
+    }
+}
+
+/// The payload of a diff chunk. Values are stored biased, as they are
+/// encoded; the accessors give the signed differences.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffRGB(u8, u8, u8);
+
+impl DiffRGB {
+    /// A diff payload from signed differences, or `None` if any is out of
+    /// range.
+    pub fn new(dr: i8, dg: i8, db: i8) -> Option<DiffRGB> {
+        let fits = [dr, dg, db].iter().all(|d| (-2..=1).contains(d));
+        fits.then(|| DiffRGB((dr + 2) as u8, (dg + 2) as u8, (db + 2) as u8))
+    }
+
+    /// The red difference from the previous pixel, `-2..=1`.
+    pub fn dr(&self) -> i8 {
+        self.0 as i8 - 2
+    }
+
+    /// The green difference from the previous pixel, `-2..=1`.
+    pub fn dg(&self) -> i8 {
+        self.1 as i8 - 2
+    }
+
+    /// The blue difference from the previous pixel, `-2..=1`.
+    pub fn db(&self) -> i8 {
+        self.2 as i8 - 2
+    }
+}
+
+/// The end marker as it reads when mistaken for index chunks.
+const END_MARKER_CHUNKS: [Chunk; 8] = [
+    Chunk::Index(Index(0)),
+    Chunk::Index(Index(0)),
+    Chunk::Index(Index(0)),
+    Chunk::Index(Index(0)),
+    Chunk::Index(Index(0)),
+    Chunk::Index(Index(0)),
+    Chunk::Index(Index(0)),
+    Chunk::Index(Index(1)),
+];
+
+impl Chunk {
+    /// The kind of chunk this is.
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Chunk::RGB(_) => Opcode::RGB,
+            Chunk::RGBA(_) => Opcode::RGBA,
+            Chunk::Index(_) => Opcode::Index,
+            Chunk::Diff(_) => Opcode::Diff,
+            Chunk::Luma(_) => Opcode::Luma,
+            Chunk::Run(_) => Opcode::Run,
+        }
+    }
+
+    /// The chunk's length in bytes, tag included.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Chunk::RGB(_) => 4,
+            Chunk::RGBA(_) => 5,
+            Chunk::Luma(_) => 2,
+            Chunk::Index(_) | Chunk::Diff(_) | Chunk::Run(_) => 1,
+        }
+    }
+
+    /// Parses the chunk starting at `data[pos]`, returning it along with its
+    /// length in bytes.
+    fn read(data: &[u8], pos: usize) -> Result<(Chunk, usize), QoiError> {
+        let payload = |n: usize| {
+            data.get(pos + 1..pos + 1 + n)
+                .ok_or(QoiError::TruncatedChunk { offset: pos })
+        };
+        let chunk = match data[pos] {
+            0b11111111 => {
+                let p = payload(4)?;
+                (Chunk::RGBA(PixelRGBA::new(p[0], p[1], p[2], p[3])), 5)
+            }
+            0b11111110 => {
+                let p = payload(3)?;
+                (Chunk::RGB(PixelRGB::new(p[0], p[1], p[2])), 4)
+            }
+            n => match n >> 6 {
+                0b00 => (Chunk::Index(Index(n)), 1),
+                0b01 => (
+                    Chunk::Diff(DiffRGB((n >> 4) & 0b11, (n >> 2) & 0b11, n & 0b11)),
+                    1,
+                ),
+                0b10 => {
+                    let p = payload(1)?;
+                    (
+                        Chunk::Luma(Luma(n & 0b00111111, p[0] >> 4, p[0] & 0b1111)),
+                        2,
+                    )
                 }
-            });
+                _ => (Chunk::Run(Run(n & 0b00111111)), 1),
+            },
+        };
+        Ok(chunk)
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match *self {
+            Chunk::RGB(PixelRGB { r, g, b }) => {
+                out.extend_from_slice(&[0b11111110, r, g, b]);
+            }
+            Chunk::RGBA(PixelRGBA { r, g, b, a }) => {
+                out.extend_from_slice(&[0b11111111, r, g, b, a]);
+            }
+            Chunk::Index(Index(i)) => {
+                out.push(i);
+            }
+            Chunk::Diff(DiffRGB(r, g, b)) => {
+                out.push((0b01 << 6) + (r << 4) + (g << 2) + b);
+            }
+            Chunk::Luma(Luma(dg, dr_dg, db_dg)) => {
+                out.push((0b10 << 6) + dg);
+                out.push((dr_dg << 4) + db_dg);
+            }
+            Chunk::Run(Run(n)) => {
+                out.push((0b11 << 6) + n);
+            }
+        }
+    }
+}
+
+/// The state a decoder carries from one chunk to the next.
+struct DecodeState {
+    // the decoder starts with PixelRGBA(0,0,0,255) as the previous pixel value.
+    prev_px: PixelRGBA,
+    // a running array[64] of PixelRGBA(0,0,0,0) is maintained by the decoder.
+    hash: [PixelRGBA; 64],
+}
+
+impl DecodeState {
+    fn new() -> DecodeState {
+        DecodeState {
+            prev_px: PixelRGBA::new(0, 0, 0, 255),
+            hash: [PixelRGBA::new(0, 0, 0, 0); 64],
+        }
+    }
+
+    /// Applies a chunk, returning the pixel it produces and how many times
+    /// that pixel repeats.
+    fn apply(&mut self, chunk: &Chunk) -> (PixelRGBA, usize) {
+        let prev = self.prev_px;
+        let px = match *chunk {
+            // simple cast to PixelRGBA
+            Chunk::RGB(PixelRGB { r, g, b }) => PixelRGBA::new(r, g, b, prev.a),
+            Chunk::RGBA(px) => px,
+            Chunk::Index(Index(i)) => self.hash[i as usize],
+            // for a Chunk::Diff(r,g,b), each of r, g, and b, is the difference from the previous pixel with a bias of 2.
+            //   0b00 => -2, 0b01 => -1, 0b10 => 0, 0b11 => 1
+            //   alpha is unchanged from prev pixel.
+            //   values wrap around at the u8 limit.
+            Chunk::Diff(DiffRGB(r, g, b)) => PixelRGBA::new(
+                prev.r.wrapping_add(r).wrapping_sub(2),
+                prev.g.wrapping_add(g).wrapping_sub(2),
+                prev.b.wrapping_add(b).wrapping_sub(2),
+                prev.a,
+            ),
+            // for a Chunk::Luma(g, dr_dg, db-dg),
+            //  g is used to indicate the general direction of change and is encoded in 6 bits.
+            //  the red and blue channels (dr and db) base their diffs off of the green channel difference
+            //      dr_dg = (cur_px.r - prev_px.r) - (cur_px.g - prev_px.g)
+            //      dr_dg = cur_px.r - prev_px.r - g
+            //      dr_dg + g = cur_px.r - prev_px.r
+            //      dr_dg + g + prev_px.r = cur_px.r
+            //  (and likewise for blue)
+            //  values are stored as unsigned integers with a bias of 32 for green channel and 8 for the red and blue channels.
+            //  values wrap around at the u8 limit.
+            //  alpha is unchanged from prev pixel.
+            Chunk::Luma(Luma(dg, dr_dg, db_dg)) => PixelRGBA::new(
+                prev.r.wrapping_add(dr_dg).wrapping_add(dg).wrapping_sub(40),
+                prev.g.wrapping_add(dg).wrapping_sub(32),
+                prev.b.wrapping_add(db_dg).wrapping_add(dg).wrapping_sub(40),
+                prev.a,
+            ),
+            // for a Chunk::Run(n), n is the number of exact copies of the previous pixel to make.
+            //  n has a bias of -1, meaning n=0 => 1.
+            Chunk::Run(Run(n)) => return (prev, n as usize + 1),
+        };
+        // every pixel value seen by the decoder is put into the array at i=(r*3 + g*5 + b*7 + a*11) % 64
+        self.hash[px.hash_index()] = px;
+        self.prev_px = px;
+        (px, 1)
+    }
+}
+
+/// The state an encoder carries from one pixel to the next.
+struct EncodeState {
+    prev_px: PixelRGBA,
+    hash: [PixelRGBA; 64],
+    run: u8,
+    mode: EncodeMode,
+}
+
+impl EncodeState {
+    fn new(mode: EncodeMode) -> EncodeState {
+        EncodeState {
+            prev_px: PixelRGBA::new(0, 0, 0, 255),
+            hash: [PixelRGBA::new(0, 0, 0, 0); 64],
+            run: 0,
+            mode,
+        }
+    }
+
+    /// The difference between two channel values, wrapped to a signed byte
+    /// in reference mode.
+    fn diff(&self, cur: u8, prev: u8) -> i32 {
+        match self.mode {
+            EncodeMode::Standard => cur as i32 - prev as i32,
+            EncodeMode::ReferenceCompatible => cur.wrapping_sub(prev) as i8 as i32,
+        }
+    }
+
+    /// Feeds the next pixel, handing any chunks it completes to `emit`.
+    fn push(&mut self, cur_px: PixelRGBA, emit: &mut impl FnMut(Chunk)) {
+        if cur_px == self.prev_px {
+            // a run chunk holds at most 62 pixels; 63 and 64 would collide
+            // with the RGB and RGBA tags.
+            self.run += 1;
+            if self.run == 62 {
+                self.flush(emit);
+            }
+            return;
+        }
+        self.flush(emit);
+
+        let i = cur_px.hash_index();
+        if self.hash[i] == cur_px {
+            emit(Chunk::Index(Index(i as u8)));
+            self.prev_px = cur_px;
+            return;
+        }
+        self.hash[i] = cur_px;
+
+        let prev_px = self.prev_px;
+        self.prev_px = cur_px;
+        if cur_px.a != prev_px.a {
+            emit(Chunk::RGBA(cur_px));
+            return;
+        }
+
+        let dr = self.diff(cur_px.r, prev_px.r);
+        let dg = self.diff(cur_px.g, prev_px.g);
+        let db = self.diff(cur_px.b, prev_px.b);
+        if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
+            emit(Chunk::Diff(DiffRGB(
+                (dr + 2) as u8,
+                (dg + 2) as u8,
+                (db + 2) as u8,
+            )));
+        } else if (-32..=31).contains(&dg)
+            && (-8..=7).contains(&(dr - dg))
+            && (-8..=7).contains(&(db - dg))
+        {
+            emit(Chunk::Luma(Luma(
+                (dg + 32) as u8,
+                (dr - dg + 8) as u8,
+                (db - dg + 8) as u8,
+            )));
+        } else {
+            emit(Chunk::RGB(PixelRGB::new(cur_px.r, cur_px.g, cur_px.b)));
+        }
+    }
+
+    /// Emits the pending run, if any.
+    fn flush(&mut self, emit: &mut impl FnMut(Chunk)) {
+        if self.run > 0 {
+            emit(Chunk::Run(Run(self.run - 1)));
+            self.run = 0;
+        }
+    }
+}
+
+/// A QOI image held as its header fields and the list of chunks that make up
+/// the pixel data.
+///
+/// Holding every chunk makes this the view for looking into or editing a
+/// stream, through [`QOIImage::chunks`] or [`asm`], at the cost of a second
+/// pass to get the pixels out. To just decode, [`decode_to_vec`] and
+/// [`decode_into`] go from the file's bytes to pixels in one pass.
+pub struct QOIImage {
+    width: u32,
+    height: u32,
+    channels: Channels,
+    color_space: ColorSpace,
+    data: Vec<Chunk>,
+}
+
+impl QOIImage {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
 
-        let mut res = header;
-        res.extend_from_slice(&image);
-        res.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn channels(&self) -> Channels {
+        self.channels
+    }
+
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    pub fn header(&self) -> Header {
+        Header {
+            width: self.width,
+            height: self.height,
+            channels: self.channels,
+            color_space: self.color_space,
+        }
+    }
+
+    /// The number of chunks in the data stream, not counting the end marker.
+    pub fn chunk_count(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Reads a QOI stream from `source`, in blocks. Any reader will do;
+    /// there is no need to wrap it in a [`std::io::BufReader`] first.
+    ///
+    /// Failures of the reader come back as [`QoiError::Io`], while a stream
+    /// that simply ends too soon is one of the truncation errors. The reader
+    /// may have been read past the end marker by the time this returns.
+    pub fn from_reader<R: std::io::Read>(source: R) -> Result<QOIImage, QoiError> {
+        QOIImage::from_reader_with_options(source, &DecodeOptions::default())
+    }
+
+    pub fn from_reader_with_options<R: std::io::Read>(
+        source: R,
+        options: &DecodeOptions,
+    ) -> Result<QOIImage, QoiError> {
+        QOIImage::read_from(&mut BlockBytes::new(source), options)
+    }
+
+    /// Decodes as much of a damaged stream as possible and pads the rest of
+    /// the image with `fill`. Only a broken header, or one over the default
+    /// [`Limits`], is an error.
+    pub fn from_reader_recovering<R: std::io::Read>(
+        source: R,
+        fill: PixelRGBA,
+    ) -> Result<(QOIImage, RecoveryReport), QoiError> {
+        QOIImage::recover_from(&mut BlockBytes::new(source), fill)
+    }
+
+    #[deprecated(note = "use `QOIImage::from_reader`, which reads in blocks")]
+    pub fn from_qoi_file<R: std::io::Read>(
+        mut source: std::io::Bytes<R>,
+    ) -> Result<QOIImage, QoiError> {
+        QOIImage::read_from(&mut source, &DecodeOptions::default())
+    }
+
+    #[deprecated(note = "use `QOIImage::from_reader_with_options`, which reads in blocks")]
+    pub fn from_qoi_file_with_options<R: std::io::Read>(
+        mut source: std::io::Bytes<R>,
+        options: &DecodeOptions,
+    ) -> Result<QOIImage, QoiError> {
+        QOIImage::read_from(&mut source, options)
+    }
+
+    #[deprecated(note = "use `QOIImage::from_reader_recovering`, which reads in blocks")]
+    pub fn from_qoi_file_recovering<R: std::io::Read>(
+        mut source: std::io::Bytes<R>,
+        fill: PixelRGBA,
+    ) -> Result<(QOIImage, RecoveryReport), QoiError> {
+        QOIImage::recover_from(&mut source, fill)
+    }
+
+    fn read_from<I: Iterator<Item = std::io::Result<u8>>>(
+        source: &mut I,
+        options: &DecodeOptions,
+    ) -> Result<QOIImage, QoiError> {
+        let header = read_header_from_bytes(source)?;
+        options.limits.check(&header)?;
+        let mut data: Vec<Chunk> = Vec::new();
+        read_chunks(source, &header, options, &mut data)?;
+        Ok(QOIImage::from_parts(header, data))
+    }
+
+    fn recover_from<I: Iterator<Item = std::io::Result<u8>>>(
+        source: &mut I,
+        fill: PixelRGBA,
+    ) -> Result<(QOIImage, RecoveryReport), QoiError> {
+        let header = read_header_from_bytes(source)?;
+        Limits::default().check(&header)?;
+        let mut data: Vec<Chunk> = Vec::new();
+        let error = read_chunks(source, &header, &DecodeOptions::default(), &mut data).err();
+
+        let px_count = header.pixel_count();
+        let pixels_recovered = data
+            .iter()
+            .map(|chunk| match chunk {
+                Chunk::Run(Run(n)) => *n as u64 + 1,
+                _ => 1,
+            })
+            .sum::<u64>()
+            .min(px_count);
+        let pixels_filled = px_count - pixels_recovered;
+        let error = match error {
+            None if pixels_filled > 0 => Some(QoiError::PixelCountMismatch {
+                expected: px_count,
+                actual: pixels_recovered,
+            }),
+            error => error,
+        };
+
+        if pixels_filled > 0 {
+            // the filler starts with a literal so it does not depend on the decoder state
+            let fill = match header.channels {
+                Channels::RGB => PixelRGBA { a: 255, ..fill },
+                Channels::RGBA => fill,
+            };
+            data.push(Chunk::RGBA(fill));
+            let mut remaining = pixels_filled - 1;
+            while remaining > 0 {
+                let n = remaining.min(62);
+                data.push(Chunk::Run(Run(n as u8 - 1)));
+                remaining -= n;
+            }
+        }
+
+        let report = RecoveryReport {
+            error,
+            pixels_recovered,
+            pixels_filled,
+        };
+        Ok((QOIImage::from_parts(header, data), report))
+    }
+
+    fn from_parts(header: Header, data: Vec<Chunk>) -> QOIImage {
+        QOIImage {
+            width: header.width,
+            height: header.height,
+            channels: header.channels,
+            color_space: header.color_space,
+            data,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(14 + self.data.len() * 2 + QOI_END_MARKER.len());
+        self.header().write_to(&mut res);
+        for chunk in &self.data {
+            chunk.write_to(&mut res);
+        }
+        res.extend_from_slice(&QOI_END_MARKER);
         res
     }
 
+    /// The pixels as rows, top row first. An image with a width of zero has
+    /// no rows at all.
+    ///
+    /// Images read from a stream always describe every pixel, but ones put
+    /// together with [`asm::assemble`] may stop short. The missing pixels
+    /// repeat the last one described, as the reference decoder fills them;
+    /// use [`QOIImage::try_to_rgba_mat`] to have that be an error instead.
     pub fn to_rgba_mat(&self) -> Vec<Vec<PixelRGBA>> {
+        self.rows().0
+    }
+
+    /// The pixels as rows, or [`QoiError::PixelCountMismatch`] if the chunks
+    /// describe fewer than `width * height` of them.
+    pub fn try_to_rgba_mat(&self) -> Result<Vec<Vec<PixelRGBA>>, QoiError> {
+        let (rows, described) = self.rows();
+        let px_count = self.header().pixel_count();
+        if described < px_count {
+            return Err(QoiError::PixelCountMismatch {
+                expected: px_count,
+                actual: described,
+            });
+        }
+        Ok(rows)
+    }
+
+    /// The pixels as rows, padded with the last pixel if the chunks stop
+    /// short, along with the number of pixels the chunks describe.
+    fn rows(&self) -> (Vec<Vec<PixelRGBA>>, u64) {
         // images are encoded row by row, left to right, top to bottom
         // an image is complete when all pixels specified by width*height have been covered.
         let width = self.width as usize;
-        let height = self.height as usize;
+        let px_count = self.header().pixel_count();
 
-        // the decoder starts with PixelRGBA(0,0,0,255) as the previous pixel value.
-        let mut prev_px = PixelRGBA(0, 0, 0, 255);
+        let mut state = DecodeState::new();
+        let mut img = Vec::with_capacity(px_count.min(self.data.len() as u64 * 62) as usize);
+        for chunk in &self.data {
+            let (px, n) = state.apply(chunk);
+            let n = (n as u64).min(px_count - img.len() as u64);
+            img.extend(std::iter::repeat_n(px, n as usize));
+        }
+        let described = img.len() as u64;
+        // a zero width image has no rows either, however tall the header
+        // says it is
+        if width == 0 {
+            return (Vec::new(), described);
+        }
+        img.resize(px_count as usize, state.prev_px);
 
-        // a running array[64] of PixelRGBA(0,0,0,0) is maintained by the decoder.
-        // every pixel value seen by the decoder is put into the array at i=(r*3 + g*5 + b*7 + a*11) % 64
-        let mut hash = [PixelRGBA(0, 0, 0, 0); 64];
+        let rows = img.chunks_exact(width).map(<[_]>::to_vec).collect();
+        (rows, described)
+    }
 
-        let mut img = Vec::with_capacity(width * height);
+    /// Decodes straight into `buf`, laid out as described by `layout`. The
+    /// buffer is checked to be big enough before anything is written to it.
+    pub fn decode_into(&self, buf: &mut [u8], layout: Layout) -> Result<(), QoiError> {
+        let mut out = layout.writer(buf, self.width, self.height)?;
+        let px_count = self.header().pixel_count();
+        let mut px_written = 0u64;
+        let mut state = DecodeState::new();
         for chunk in &self.data {
-            match chunk {
-                &Chunk::RGB(PixelRGB(r, g, b)) => {
-                    // simple cast to PixelRGBA
-                    img.push(PixelRGBA(r, g, b, prev_px.3));
-                    hash[(r as usize * 3
-                        + g as usize * 5
-                        + b as usize * 7
-                        + prev_px.3 as usize * 11)
-                        % 64] = PixelRGBA(r, g, b, prev_px.3);
-                    prev_px = PixelRGBA(r, g, b, prev_px.3);
+            let (px, n) = state.apply(chunk);
+            let n = (n as u64).min(px_count - px_written);
+            for _ in 0..n {
+                out.push(px);
+            }
+            px_written += n;
+        }
+        if px_written < px_count {
+            return Err(QoiError::PixelCountMismatch {
+                expected: px_count,
+                actual: px_written,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn from_rgba_mat(src: &[Vec<PixelRGBA>], width: usize, height: usize) -> QOIImage {
+        QOIImage::encode_rgba_mat(src, width, height, &EncodeOptions::default())
+    }
+
+    pub fn from_rgba_mat_with_options(
+        src: &[Vec<PixelRGBA>],
+        width: usize,
+        height: usize,
+        options: &EncodeOptions,
+    ) -> Result<QOIImage, QoiError> {
+        // the channel count is not known until every pixel has been seen, so assume the worst
+        check_mat_limits(
+            width,
+            height,
+            options.channels.unwrap_or(Channels::RGBA),
+            options,
+        )?;
+        Ok(QOIImage::encode_rgba_mat(src, width, height, options))
+    }
+
+    fn encode_rgba_mat(
+        src: &[Vec<PixelRGBA>],
+        width: usize,
+        height: usize,
+        options: &EncodeOptions,
+    ) -> QOIImage {
+        let mut is_transparent = false;
+        let mut state = EncodeState::new(options.mode);
+        let mut data: Vec<Chunk> = Vec::new();
+        let mut emit = |chunk| data.push(chunk);
+        for cur_px in src.iter().flatten() {
+            // determine channels
+            if cur_px.a != 255 {
+                is_transparent = true;
+            }
+            // a three channel image has nowhere to put alpha, so treat every pixel as opaque
+            let cur_px = match options.channels {
+                Some(Channels::RGB) => PixelRGBA { a: 255, ..*cur_px },
+                _ => *cur_px,
+            };
+            state.push(cur_px, &mut emit);
+        }
+        state.flush(&mut emit);
+
+        let channels = options.channels.unwrap_or(if is_transparent {
+            Channels::RGBA
+        } else {
+            Channels::RGB
+        });
+        QOIImage {
+            width: width as u32,
+            height: height as u32,
+            channels,
+            color_space: options.color_space,
+            data,
+        }
+    }
+
+    pub fn from_rgb_mat(src: &[Vec<PixelRGB>], width: usize, height: usize) -> QOIImage {
+        QOIImage::encode_rgb_mat(src, width, height, &EncodeOptions::default())
+    }
+
+    /// Encodes opaque pixels. The header says RGB unless `options` forces RGBA.
+    pub fn from_rgb_mat_with_options(
+        src: &[Vec<PixelRGB>],
+        width: usize,
+        height: usize,
+        options: &EncodeOptions,
+    ) -> Result<QOIImage, QoiError> {
+        check_mat_limits(
+            width,
+            height,
+            options.channels.unwrap_or(Channels::RGB),
+            options,
+        )?;
+        Ok(QOIImage::encode_rgb_mat(src, width, height, options))
+    }
+
+    fn encode_rgb_mat(
+        src: &[Vec<PixelRGB>],
+        width: usize,
+        height: usize,
+        options: &EncodeOptions,
+    ) -> QOIImage {
+        let data = encode_chunks(
+            src.iter().flatten().map(|&px| PixelRGBA::from(px)),
+            options.mode,
+        );
+        QOIImage {
+            width: width as u32,
+            height: height as u32,
+            channels: options.channels.unwrap_or(Channels::RGB),
+            color_space: options.color_space,
+            data,
+        }
+    }
+
+    /// Encodes packed pixels, three or four bytes each depending on
+    /// `header.channels`. Three byte pixels are taken as they are, with an
+    /// implicit alpha of 255.
+    pub fn from_packed(pixels: &[u8], header: Header) -> Result<QOIImage, QoiError> {
+        QOIImage::from_packed_with_mode(pixels, header, EncodeMode::default())
+    }
+
+    pub fn from_packed_with_mode(
+        pixels: &[u8],
+        header: Header,
+        mode: EncodeMode,
+    ) -> Result<QOIImage, QoiError> {
+        check_input_len(pixels, &header)?;
+        let data = encode_chunks(packed_pixels(pixels, header.channels), mode);
+        Ok(QOIImage::from_parts(header, data))
+    }
+}
+
+/// Decodes a complete QOI file held in memory into packed pixels, three or four
+/// bytes each depending on the header's channel count.
+pub fn decode_to_vec(data: &[u8]) -> Result<(Header, Vec<u8>), QoiError> {
+    decode_to_vec_with_options(data, &DecodeOptions::default())
+}
+
+pub fn decode_to_vec_with_options(
+    data: &[u8],
+    options: &DecodeOptions,
+) -> Result<(Header, Vec<u8>), QoiError> {
+    let header = read_header(data)?;
+    options.limits.check(&header)?;
+    let (pixels, res) = decode_packed(data, &header, options);
+    res.map(|()| (header, pixels))
+}
+
+/// Decodes as much of a damaged stream as possible and pads the rest of the
+/// image with `fill`. Only a broken header, or one over the default
+/// [`Limits`], is an error.
+pub fn decode_to_vec_recovering(
+    data: &[u8],
+    fill: PixelRGBA,
+) -> Result<(Header, Vec<u8>, RecoveryReport), QoiError> {
+    let header = read_header(data)?;
+    Limits::default().check(&header)?;
+    let (mut pixels, res) = decode_packed(data, &header, &DecodeOptions::default());
+    let error = res.err();
+    let channels = header.channels.count();
+
+    let pixels_recovered = (pixels.len() / channels) as u64;
+    let pixels_filled = header.pixel_count() - pixels_recovered;
+    let fill = <[u8; 4]>::from(fill);
+    for _ in 0..pixels_filled {
+        pixels.extend_from_slice(&fill[..channels]);
+    }
+
+    let report = RecoveryReport {
+        error,
+        pixels_recovered,
+        pixels_filled,
+    };
+    Ok((header, pixels, report))
+}
+
+/// Decodes a complete QOI file held in memory straight into `buf`, laid out
+/// as described by `layout`. The buffer is checked to be big enough before
+/// anything is written to it.
+pub fn decode_into(data: &[u8], buf: &mut [u8], layout: Layout) -> Result<Header, QoiError> {
+    let header = read_header(data)?;
+    let mut out = layout.writer(buf, header.width, header.height)?;
+    decode_pixels(data, &header, &DecodeOptions::default(), &mut |px, n| {
+        for _ in 0..n {
+            out.push(px);
+        }
+    })?;
+    Ok(header)
+}
+
+/// How many bytes of packed pixels a stream can decode to, trusting the header
+/// no further than the 62 pixels each chunk byte can make.
+fn pixel_capacity(data: &[u8], header: &Header) -> usize {
+    let chunk_bytes = data.len().saturating_sub(14) as u64;
+    header.pixel_count().min(chunk_bytes * 62) as usize * header.channels.count()
+}
+
+/// Decodes the chunks of `data` into packed pixels, three or four bytes each
+/// depending on the header's channel count. On error the pixels stop where
+/// the stream broke.
+fn decode_packed(
+    data: &[u8],
+    header: &Header,
+    options: &DecodeOptions,
+) -> (Vec<u8>, Result<(), QoiError>) {
+    let mut pixels = vec![0; pixel_capacity(data, header)];
+    let mut len = 0;
+    // a copy of the loop for each pixel size keeps the writes fixed-length
+    let res = match header.channels {
+        Channels::RGB => decode_pixels(data, header, options, &mut |px, n| {
+            fill::<3>(&mut pixels, &mut len, px, n)
+        }),
+        Channels::RGBA => decode_pixels(data, header, options, &mut |px, n| {
+            fill::<4>(&mut pixels, &mut len, px, n)
+        }),
+    };
+    pixels.truncate(len);
+    (pixels, res)
+}
+
+/// Writes `n` copies of a pixel, `N` bytes each, at `buf[*len..]`.
+fn fill<const N: usize>(buf: &mut [u8], len: &mut usize, px: PixelRGBA, n: usize) {
+    let bytes = <[u8; 4]>::from(px);
+    let end = *len + n * N;
+    for out in buf[*len..end].chunks_exact_mut(N) {
+        out.copy_from_slice(&bytes[..N]);
+    }
+    *len = end;
+}
+
+/// Decodes the chunks of `data` in a single pass, handing each pixel to `out`
+/// in order along with how many times it repeats. On error `out` has seen
+/// every pixel decoded before the failure.
+///
+/// This is the hot path behind every decoder that starts from a byte slice.
+/// It reads the bytes directly rather than going through [`Chunk`], but must
+/// agree with [`DecodeState::apply`] on what every chunk means.
+fn decode_pixels(
+    data: &[u8],
+    header: &Header,
+    options: &DecodeOptions,
+    out: &mut impl FnMut(PixelRGBA, usize),
+) -> Result<(), QoiError> {
+    // the chunks are followed by the end marker, which is never read as chunks.
+    // a stream without one is read to the end, so a truncated stream gives up
+    // every pixel it still holds.
+    let has_end_marker = data.len() >= 14 + QOI_END_MARKER.len() && data.ends_with(&QOI_END_MARKER);
+    let chunks_end = if has_end_marker {
+        data.len() - QOI_END_MARKER.len()
+    } else {
+        data.len()
+    };
+
+    let px_count = header.pixel_count();
+    let mut prev = PixelRGBA::new(0, 0, 0, 255);
+    let mut hash = [PixelRGBA::new(0, 0, 0, 0); 64];
+    let mut px_written = 0u64;
+    let mut pos = 14;
+    while px_written < px_count {
+        if pos >= chunks_end {
+            if !has_end_marker {
+                return Err(QoiError::MissingEndMarker { offset: data.len() });
+            }
+            return Err(QoiError::PixelCountMismatch {
+                expected: px_count,
+                actual: px_written,
+            });
+        }
+        let chunk_offset = pos;
+        let payload = |n: usize| {
+            data.get(chunk_offset + 1..chunk_offset + 1 + n)
+                .ok_or(QoiError::TruncatedChunk {
+                    offset: chunk_offset,
+                })
+        };
+
+        let tag = data[pos];
+        let px = match tag {
+            0b11111110 => {
+                let p = payload(3)?;
+                pos += 4;
+                PixelRGBA::new(p[0], p[1], p[2], prev.a)
+            }
+            0b11111111 => {
+                let p = payload(4)?;
+                pos += 5;
+                PixelRGBA::new(p[0], p[1], p[2], p[3])
+            }
+            _ => match tag >> 6 {
+                0b00 => {
+                    pos += 1;
+                    hash[tag as usize]
                 }
-                &Chunk::RGBA(px) => {
-                    img.push(px);
-                    hash[(px.0 as usize * 3
-                        + px.1 as usize * 5
-                        + px.2 as usize * 7
-                        + px.3 as usize * 11)
-                        % 64] = px;
-                    prev_px = px;
+                0b01 => {
+                    pos += 1;
+                    PixelRGBA::new(
+                        prev.r.wrapping_add((tag >> 4) & 0b11).wrapping_sub(2),
+                        prev.g.wrapping_add((tag >> 2) & 0b11).wrapping_sub(2),
+                        prev.b.wrapping_add(tag & 0b11).wrapping_sub(2),
+                        prev.a,
+                    )
                 }
-                &Chunk::Index(i) => {
-                    img.push(hash[i as usize]);
-                    prev_px = hash[i as usize];
+                0b10 => {
+                    let drb = payload(1)?[0];
+                    pos += 2;
+                    let dg = (tag & 0b00111111).wrapping_sub(32);
+                    PixelRGBA::new(
+                        prev.r
+                            .wrapping_add(dg)
+                            .wrapping_add(drb >> 4)
+                            .wrapping_sub(8),
+                        prev.g.wrapping_add(dg),
+                        prev.b
+                            .wrapping_add(dg)
+                            .wrapping_add(drb & 0b1111)
+                            .wrapping_sub(8),
+                        prev.a,
+                    )
                 }
-                &Chunk::Diff(DiffRGB(r, g, b)) => {
-                    // for a Chunk::Diff(r,g,b), each of r, g, and b, is the difference from the previous pixel with a bias of 2.
-                    //   0b00 => -2, 0b01 => -1, 0b10 => 0, 0b11 => 1
-                    //   alpha is unchanged from prev pixel.
-                    //   values wrap around at the u8 limit.
-                    let cr = (prev_px.0).wrapping_add(r).wrapping_sub(2);
-                    let cg = (prev_px.1).wrapping_add(g).wrapping_sub(2);
-                    let cb = (prev_px.2).wrapping_add(b).wrapping_sub(2);
-                    img.push(PixelRGBA(cr, cg, cb, prev_px.3));
-                    hash[(cr as usize * 3
-                        + cg as usize * 5
-                        + cb as usize * 7
-                        + prev_px.3 as usize * 11)
-                        % 64] = PixelRGBA(cr, cg, cb, prev_px.3);
-                    prev_px = PixelRGBA(cr, cg, cb, prev_px.3);
+                _ => {
+                    // a run repeats the previous pixel and leaves the index alone
+                    pos += 1;
+                    let n = (tag & 0b00111111) as u64 + 1;
+                    let remaining = px_count - px_written;
+                    if options.strict && n > remaining {
+                        return Err(QoiError::RunOverflow {
+                            offset: chunk_offset,
+                        });
+                    }
+                    let n = n.min(remaining);
+                    out(prev, n as usize);
+                    px_written += n;
+                    continue;
                 }
-                &Chunk::Luma(Luma(dg, dr_dg, db_dg)) => {
-                    // for a Chunk::Luma(g, dr_dg, db-dg),
-                    //  g is used to indicate the general direction of change and is encoded in 6 bits.
-                    //  the red and blue channels (dr and db) base their diffs off of the green channel difference
-                    //      dr_dg = (cur_px.r - prev_px.r) - (cur_px.g - prev_px.g)
-                    //      dr_dg = cur_px.r - prev_px.r - g
-                    //      dr_dg + g = cur_px.r - prev_px.r
-                    //      dr_dg + g + prev_px.r = cur_px.r
-
-                    //      db_dg = (cur_px.b - prev_px.b) - (cur_px.g - prev_px.g)
-                    //      dr_dg = cur_px.b - prev_px.b - g
-                    //      dr_dg + g = cur_px.b - prev_px.b
-                    //      dr_dg + g + prev_px.b = cur_px.b
-                    //  values are stored as unsigned integers with a bias of 32 for green channel and 8 for the red and blue channels.
-                    //  values wrap around at the u8 limit.
-                    //  alpha is unchanged from prev pixel.
-                    let cr = (prev_px.0)
-                        .wrapping_add(dr_dg)
-                        .wrapping_add(dg)
-                        .wrapping_sub(40);
-                    let cg = (prev_px.1).wrapping_add(dg).wrapping_sub(32);
-                    let cb = (prev_px.2)
-                        .wrapping_add(db_dg)
-                        .wrapping_add(dg)
-                        .wrapping_sub(40);
-                    img.push(PixelRGBA(cr, cg, cb, prev_px.3));
-                    hash[(cr as usize * 3
-                        + cg as usize * 5
-                        + cb as usize * 7
-                        + prev_px.3 as usize * 11)
-                        % 64] = PixelRGBA(cr, cg, cb, prev_px.3);
-                    prev_px = PixelRGBA(cr, cg, cb, prev_px.3);
+            },
+        };
+        hash[px.hash_index()] = px;
+        prev = px;
+        out(px, 1);
+        px_written += 1;
+    }
+
+    if !options.strict {
+        // as in read_chunks, chunks past the last pixel run up to the first
+        // end marker and their pixels are dropped
+        while !data[pos..].starts_with(&QOI_END_MARKER) {
+            let len = match data.get(pos) {
+                None => return Err(QoiError::MissingEndMarker { offset: pos }),
+                Some(0b11111110) => 4,
+                Some(0b11111111) => 5,
+                Some(tag) if tag >> 6 == 0b10 => 2,
+                Some(_) => 1,
+            };
+            if pos + len > data.len() {
+                return Err(QoiError::TruncatedChunk { offset: pos });
+            }
+            pos += len;
+        }
+        return Ok(());
+    }
+
+    if data.get(pos..pos + QOI_END_MARKER.len()) != Some(&QOI_END_MARKER[..]) {
+        return Err(QoiError::MissingEndMarker { offset: pos });
+    }
+    if options.strict && pos + QOI_END_MARKER.len() != data.len() {
+        return Err(QoiError::TrailingData {
+            offset: pos + QOI_END_MARKER.len(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Encodes packed pixels, three or four bytes each depending on
+/// `header.channels`, into a complete QOI file.
+pub fn encode_to_vec(pixels: &[u8], header: Header) -> Result<Vec<u8>, QoiError> {
+    encode_to_vec_with_mode(pixels, header, EncodeMode::default())
+}
+
+pub fn encode_to_vec_with_mode(
+    pixels: &[u8],
+    header: Header,
+    mode: EncodeMode,
+) -> Result<Vec<u8>, QoiError> {
+    check_input_len(pixels, &header)?;
+
+    let mut res = Vec::with_capacity(14 + pixels.len() / 2 + QOI_END_MARKER.len());
+    header.write_to(&mut res);
+
+    let mut state = EncodeState::new(mode);
+    let mut emit = |chunk: Chunk| chunk.write_to(&mut res);
+    for px in packed_pixels(pixels, header.channels) {
+        state.push(px, &mut emit);
+    }
+    state.flush(&mut emit);
+
+    res.extend_from_slice(&QOI_END_MARKER);
+    Ok(res)
+}
+
+fn check_mat_limits(
+    width: usize,
+    height: usize,
+    channels: Channels,
+    options: &EncodeOptions,
+) -> Result<(), QoiError> {
+    let too_big = |kind, value: usize, max: u32| QoiError::LimitExceeded {
+        kind,
+        value: value as u64,
+        max: max as u64,
+    };
+    let header = Header {
+        width: width
+            .try_into()
+            .map_err(|_| too_big(LimitKind::Width, width, u32::MAX))?,
+        height: height
+            .try_into()
+            .map_err(|_| too_big(LimitKind::Height, height, u32::MAX))?,
+        channels,
+        color_space: options.color_space,
+    };
+    options.limits.check(&header)
+}
+
+fn check_input_len(pixels: &[u8], header: &Header) -> Result<(), QoiError> {
+    let expected = header
+        .pixel_count()
+        .saturating_mul(header.channels.count() as u64);
+    if pixels.len() as u64 != expected {
+        return Err(QoiError::BadInputLength {
+            expected,
+            actual: pixels.len() as u64,
+        });
+    }
+    Ok(())
+}
+
+/// Reads packed three or four byte pixels.
+fn packed_pixels(pixels: &[u8], channels: Channels) -> impl Iterator<Item = PixelRGBA> + '_ {
+    pixels.chunks_exact(channels.count()).map(|px| match *px {
+        [r, g, b, a] => PixelRGBA::new(r, g, b, a),
+        _ => PixelRGBA::new(px[0], px[1], px[2], 255),
+    })
+}
+
+fn encode_chunks(pixels: impl Iterator<Item = PixelRGBA>, mode: EncodeMode) -> Vec<Chunk> {
+    let mut state = EncodeState::new(mode);
+    let mut data: Vec<Chunk> = Vec::new();
+    let mut emit = |chunk| data.push(chunk);
+    for px in pixels {
+        state.push(px, &mut emit);
+    }
+    state.flush(&mut emit);
+    data
+}
+
+/// Reads just the 14 byte header from the front of `source`, leaving it
+/// positioned at the first chunk.
+pub fn read_header<R: std::io::Read>(mut source: R) -> Result<Header, QoiError> {
+    let mut header = [0u8; 14];
+    let mut len = 0;
+    while len < header.len() {
+        match source.read(&mut header[len..]) {
+            Ok(0) => return Err(QoiError::TruncatedHeader { len }),
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(QoiError::Io(e)),
+        }
+    }
+    Header::from_bytes(&header)
+}
+
+/// The bytes of a reader, one at a time, read in 8 KiB blocks. Unlike
+/// [`std::io::Bytes`] this costs an index into the block per byte rather
+/// than a `read` call.
+pub(crate) struct BlockBytes<R> {
+    source: R,
+    block: Box<[u8]>,
+    pos: usize,
+    len: usize,
+}
+
+impl<R: std::io::Read> BlockBytes<R> {
+    pub(crate) fn new(source: R) -> Self {
+        BlockBytes {
+            source,
+            block: vec![0; 8192].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// Gives back the reader, dropping whatever is left of the current block.
+    pub(crate) fn into_inner(self) -> R {
+        self.source
+    }
+
+    #[cold]
+    fn refill(&mut self) -> Option<std::io::Result<u8>> {
+        loop {
+            match self.source.read(&mut self.block) {
+                Ok(0) => return None,
+                Ok(n) => {
+                    self.pos = 1;
+                    self.len = n;
+                    return Some(Ok(self.block[0]));
                 }
-                &Chunk::Run(n) => {
-                    // for a Chunk::Run(n), n is the number of exact copies of the previous pixel to make.
-                    //  n has a bias of -1, meaning n=0 => 1.
-                    for _ in 0..n + 1 {
-                        img.push(prev_px);
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<R: std::io::Read> Iterator for BlockBytes<R> {
+    type Item = std::io::Result<u8>;
+
+    #[inline]
+    fn next(&mut self) -> Option<std::io::Result<u8>> {
+        if self.pos < self.len {
+            self.pos += 1;
+            Some(Ok(self.block[self.pos - 1]))
+        } else {
+            self.refill()
+        }
+    }
+}
+
+/// Reads the 14 byte header from the front of a stream.
+fn read_header_from_bytes<I: Iterator<Item = std::io::Result<u8>>>(
+    source: &mut I,
+) -> Result<Header, QoiError> {
+    let mut header = [0u8; 14];
+    for (i, byte) in header.iter_mut().enumerate() {
+        match source.next() {
+            Some(Ok(x)) => *byte = x,
+            Some(Err(e)) => return Err(QoiError::Io(e)),
+            None => return Err(QoiError::TruncatedHeader { len: i }),
+        }
+    }
+    Header::from_bytes(&header)
+}
+
+/// Reads the chunks that follow the header into `data`, up to and including
+/// the end marker. On error `data` holds every chunk read before the failure.
+fn read_chunks<I: Iterator<Item = std::io::Result<u8>>>(
+    source: &mut I,
+    header: &Header,
+    options: &DecodeOptions,
+    data: &mut Vec<Chunk>,
+) -> Result<(), QoiError> {
+    // byte offset of the next byte to be read from the source
+    let mut offset = 14;
+
+    let px_count = header.pixel_count();
+    let mut px_decoded = 0u64;
+
+    // build the chunks
+    let mut zeroes_so_far = 0;
+    loop {
+        // strict mode finds the end of the chunks by counting pixels
+        if options.strict && px_decoded == px_count {
+            break;
+        }
+
+        let chunk_offset = offset;
+        let current_chunk = match source.next() {
+            Some(Ok(x)) => x,
+            Some(Err(e)) => return Err(QoiError::Io(e)),
+            None if px_decoded < px_count && data.ends_with(&END_MARKER_CHUNKS) => {
+                return Err(QoiError::PixelCountMismatch {
+                    expected: px_count,
+                    actual: px_decoded - END_MARKER_CHUNKS.len() as u64,
+                })
+            }
+            None => return Err(QoiError::MissingEndMarker { offset }),
+        };
+        offset += 1;
+
+        match current_chunk {
+            0b11111111 => {
+                let [r, g, b, a] = read_payload(source, chunk_offset, &mut offset)?;
+                data.push(Chunk::RGBA(PixelRGBA::new(r, g, b, a)));
+                zeroes_so_far = 0;
+            }
+            0b11111110 => {
+                let [r, g, b] = read_payload(source, chunk_offset, &mut offset)?;
+                data.push(Chunk::RGB(PixelRGB::new(r, g, b)));
+                zeroes_so_far = 0;
+            }
+            n if n >> 6 == 0b11 => {
+                data.push(Chunk::Run(Run(n & 0b00111111)));
+                zeroes_so_far = 0;
+            }
+            n if n >> 6 == 0b00 => {
+                // the end marker is seven 0x00 bytes followed by a single 0x01.
+                // before the last pixel those are index chunks like any other,
+                // so only zeroes read after it count towards one
+                if n == 1 && zeroes_so_far >= 7 && !options.strict {
+                    for _ in 0..7 {
+                        data.pop();
                     }
+                    break;
+                }
+                if n == 0 && px_decoded >= px_count {
+                    zeroes_so_far += 1;
+                } else {
+                    zeroes_so_far = 0;
                 }
+                data.push(Chunk::Index(Index(n & 0b00111111)));
+            }
+            n if n >> 6 == 0b01 => {
+                let r: u8 = (n & 0b00110000) >> 4;
+                let g: u8 = (n & 0b00001100) >> 2;
+                let b: u8 = n & 0b11;
+                data.push(Chunk::Diff(DiffRGB(r, g, b)));
+                zeroes_so_far = 0;
+            }
+            n => {
+                // n >> 6 == 0b10
+                let dg = n & 0b00111111;
+                let [next_byte] = read_payload(source, chunk_offset, &mut offset)?;
+                // dg = 6 bit green channel difference from the previous pixel -32..31
+                // next_byte = ((current_pixel.r - prev_pixel.r) - (current_pixel.g - prev_pixel.g) << 4)
+                //           + ((current_pixel.b - prev_pixel.b) - (current_pixel.g - prev_pixel.g)
+                let dr_dg = next_byte >> 4;
+                let db_dg = next_byte & 0b1111;
+                data.push(Chunk::Luma(Luma(dg, dr_dg, db_dg)));
+                zeroes_so_far = 0;
             }
         }
 
-        let mut res = Vec::with_capacity(height);
-        let mut cur_px = 0;
-        for _ in 0..height {
-            let mut row = Vec::with_capacity(width);
-            for _ in 0..width {
-                row.push(img[cur_px]);
-                cur_px += 1;
-            }
-            res.push(row);
-        }
-        res
+        let n = match data.last() {
+            Some(Chunk::Run(Run(n))) => *n as u64 + 1,
+            _ => 1,
+        };
+        if options.strict && px_decoded + n > px_count {
+            return Err(QoiError::RunOverflow {
+                offset: chunk_offset,
+            });
+        }
+        px_decoded += n;
+    }
+
+    if options.strict {
+        let marker_offset = offset;
+        for expected in QOI_END_MARKER {
+            match source.next() {
+                Some(Ok(x)) if x == expected => offset += 1,
+                Some(Err(e)) => return Err(QoiError::Io(e)),
+                _ => {
+                    return Err(QoiError::MissingEndMarker {
+                        offset: marker_offset,
+                    })
+                }
+            }
+        }
+        match source.next() {
+            None => {}
+            Some(Ok(_)) => return Err(QoiError::TrailingData { offset }),
+            Some(Err(e)) => return Err(QoiError::Io(e)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the `N` payload bytes following the tag of the chunk at `chunk_offset`.
+fn read_payload<I: Iterator<Item = std::io::Result<u8>>, const N: usize>(
+    source: &mut I,
+    chunk_offset: usize,
+    offset: &mut usize,
+) -> Result<[u8; N], QoiError> {
+    let mut payload = [0u8; N];
+    for byte in payload.iter_mut() {
+        match source.next() {
+            Some(Ok(x)) => *byte = x,
+            Some(Err(e)) => return Err(QoiError::Io(e)),
+            None => {
+                return Err(QoiError::TruncatedChunk {
+                    offset: chunk_offset,
+                })
+            }
+        }
+        *offset += 1;
+    }
+    Ok(payload)
+}
+
+/// A single 8-bit-per-channel pixel with straight (non-premultiplied) alpha.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PixelRGBA {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl PixelRGBA {
+    pub const fn new(r: u8, g: u8, b: u8, a: u8) -> PixelRGBA {
+        PixelRGBA { r, g, b, a }
+    }
+
+    /// The slot this pixel occupies in the 64 entry index.
+    fn hash_index(self) -> usize {
+        (self.r as usize * 3 + self.g as usize * 5 + self.b as usize * 7 + self.a as usize * 11)
+            % 64
+    }
+}
+
+impl From<[u8; 4]> for PixelRGBA {
+    fn from([r, g, b, a]: [u8; 4]) -> Self {
+        PixelRGBA { r, g, b, a }
+    }
+}
+
+impl From<PixelRGBA> for [u8; 4] {
+    fn from(px: PixelRGBA) -> Self {
+        [px.r, px.g, px.b, px.a]
+    }
+}
+
+/// Unpacks `0xRRGGBBAA`.
+impl From<u32> for PixelRGBA {
+    fn from(rgba: u32) -> Self {
+        PixelRGBA::from(rgba.to_be_bytes())
+    }
+}
+
+/// Packs into `0xRRGGBBAA`.
+impl From<PixelRGBA> for u32 {
+    fn from(px: PixelRGBA) -> Self {
+        u32::from_be_bytes(px.into())
+    }
+}
+
+/// Widens to an opaque pixel.
+impl From<PixelRGB> for PixelRGBA {
+    fn from(px: PixelRGB) -> Self {
+        PixelRGBA::new(px.r, px.g, px.b, 255)
     }
+}
 
-    pub fn from_rgba_mat(src: &Vec<Vec<PixelRGBA>>, width: usize, height: usize) -> QOIImage {
-        let mut is_transparent = false;
-        let mut prev_px = PixelRGBA(0, 0, 0, 255);
-        let mut hash = [PixelRGBA(0, 0, 0, 0); 64];
-        let src = src.iter().flatten();
-        let mut data: Vec<Chunk> = Vec::new();
-        data.push(Chunk::RGBA(prev_px));
-        for cur_px in src {
-            // determine channels
-            if cur_px.3 != 255 {
-                is_transparent = true;
-            }
+/// A single 8-bit-per-channel pixel without an alpha channel.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PixelRGB {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
 
-            // check if this is a run
-            if (cur_px.0, cur_px.1, cur_px.2, cur_px.3)
-                == (prev_px.0, prev_px.1, prev_px.2, prev_px.3)
-            {
-                if let Chunk::Run(i) = data.last_mut().unwrap() {
-                    *i += 1;
-                } else {
-                    data.push(Chunk::Run(0));
-                }
-                continue;
-            }
-
-            // check if this is appropriately an index
-            let tmp = hash[(cur_px.0 as usize * 3
-                + cur_px.1 as usize * 5
-                + cur_px.2 as usize * 7
-                + cur_px.3 as usize * 11)
-                % 64];
-            if (cur_px.0, cur_px.1, cur_px.2, cur_px.3) == (tmp.0, tmp.1, tmp.2, tmp.3) {
-                data.push(Chunk::Index(
-                    ((cur_px.0 as usize * 3
-                        + cur_px.1 as usize * 5
-                        + cur_px.2 as usize * 7
-                        + cur_px.3 as usize * 11)
-                        % 64) as u8,
-                ));
-                prev_px = *cur_px;
-                continue;
-            }
-
-            // does this pixel change opacity from the last one?
-            if cur_px.3 == prev_px.3 {
-                // no => DIFF, LUMA, RGB
-                let dr = cur_px.0 as i32 - prev_px.0 as i32;
-                let dg = cur_px.1 as i32 - prev_px.1 as i32;
-                let db = cur_px.2 as i32 - prev_px.2 as i32;
-
-                // check if this pixel could be a DIFF
-                if (dr >= -2 && dr <= 1) && (dg >= -2 && dg <= 1) && (db >= -2 && db <= 1) {
-                    data.push(Chunk::Diff(DiffRGB(
-                        (dr + 2) as u8,
-                        (dg + 2) as u8,
-                        (db + 2) as u8,
-                    )));
-                    hash[(cur_px.0 as usize * 3
-                        + cur_px.1 as usize * 5
-                        + cur_px.2 as usize * 7
-                        + cur_px.3 as usize * 11)
-                        % 64] = *cur_px;
-                    prev_px = *cur_px;
-                    continue;
-                }
-                // check if this pixel could be a LUMA
-                //  if (-32 <= dg <= 31) then the green channel qualifies
-                //      if (-8 <= (dr - dg) <= 7) && (-8 <= (db - dg) <= 7) then the red and blue channels qualify
-                if (dg >= -32 && dg <= 31)
-                    && ((dr - dg) >= -8 && (dr - dg) <= 7)
-                    && ((db - dg) >= -8 && (db - dg) <= 7)
-                {
-                    data.push(Chunk::Luma(Luma(
-                        (dg + 32) as u8,
-                        (dr - dg + 8) as u8,
-                        (db - dg + 8) as u8,
-                    )));
-                    hash[(cur_px.0 as usize * 3
-                        + cur_px.1 as usize * 5
-                        + cur_px.2 as usize * 7
-                        + cur_px.3 as usize * 11)
-                        % 64] = *cur_px;
-                    prev_px = *cur_px;
-                    continue;
-                }
+impl PixelRGB {
+    pub const fn new(r: u8, g: u8, b: u8) -> PixelRGB {
+        PixelRGB { r, g, b }
+    }
+}
 
-                // otherwise it has to be an RGB
-                data.push(Chunk::RGB(PixelRGB(cur_px.0, cur_px.1, cur_px.2)));
-                hash[(cur_px.0 as usize * 3
-                    + cur_px.1 as usize * 5
-                    + cur_px.2 as usize * 7
-                    + cur_px.3 as usize * 11)
-                    % 64] = *cur_px;
-                prev_px = *cur_px;
-                continue;
-            } else {
-                //  yes => RGBA
-                data.push(Chunk::RGBA(*cur_px));
-                hash[(cur_px.0 as usize * 3
-                    + cur_px.1 as usize * 5
-                    + cur_px.2 as usize * 7
-                    + cur_px.3 as usize * 11)
-                    % 64] = *cur_px;
-                prev_px = *cur_px;
-                continue;
-            }
-        }
-
-        data.remove(0);
-        let mut channels = Channels::RGB;
-        if is_transparent {
-            channels = Channels::RGBA;
-        }
-        QOIImage {
-            width: width as u32,
-            height: height as u32,
-            channels,
-            color_space: ColorSpace::Linear,
-            data,
-        }
+impl From<[u8; 3]> for PixelRGB {
+    fn from([r, g, b]: [u8; 3]) -> Self {
+        PixelRGB { r, g, b }
+    }
+}
+
+impl From<PixelRGB> for [u8; 3] {
+    fn from(px: PixelRGB) -> Self {
+        [px.r, px.g, px.b]
+    }
+}
+
+/// Unpacks `0x00RRGGBB`; the top byte is ignored.
+impl From<u32> for PixelRGB {
+    fn from(rgb: u32) -> Self {
+        let [_, r, g, b] = rgb.to_be_bytes();
+        PixelRGB { r, g, b }
+    }
+}
+
+/// Drops the alpha channel.
+impl From<PixelRGBA> for PixelRGB {
+    fn from(px: PixelRGBA) -> Self {
+        PixelRGB::new(px.r, px.g, px.b)
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct PixelRGBA(u8, u8, u8, u8);
+/// The dice test image as rows of pixels, for the format modules' tests.
+#[cfg(test)]
+pub(crate) fn dice_mat() -> Vec<Vec<PixelRGBA>> {
+    let data = std::fs::read("files/dice.qoi").unwrap();
+    QOIImage::from_reader(data.as_slice())
+        .unwrap()
+        .to_rgba_mat()
+}
 
-#[derive(Copy, Clone)]
-pub struct PixelRGB(u8, u8, u8);
+/// The dice test image as packed pixels, with its header.
+#[cfg(test)]
+pub(crate) fn dice_packed() -> (Header, Vec<u8>) {
+    decode_to_vec(&std::fs::read("files/dice.qoi").unwrap()).unwrap()
+}
 
 #[cfg(test)]
 mod tests {
@@ -536,44 +1928,35 @@ mod tests {
 
     #[test]
     fn parse_dice() {
-        QOIImage::from_qoi_file(BufReader::new(File::open("files/dice.qoi").unwrap()).bytes())
-            .unwrap();
+        QOIImage::from_reader(File::open("files/dice.qoi").unwrap()).unwrap();
     }
 
     #[test]
     fn deserialize_reserialize_dice() {
-        let dice =
-            QOIImage::from_qoi_file(BufReader::new(File::open("files/dice.qoi").unwrap()).bytes())
-                .unwrap();
+        let dice = QOIImage::from_reader(File::open("files/dice.qoi").unwrap()).unwrap();
         std::fs::write("files/dice2.qoi", dice.serialize()).unwrap();
 
-        let file1 = BufReader::new(File::open("files/dice.qoi").unwrap()).bytes();
-        let file2 = BufReader::new(File::open("files/dice2.qoi").unwrap()).bytes();
-        for (b1, b2) in file1.zip(file2) {
-            assert!(b1.unwrap() == b2.unwrap());
-        }
+        let file1 = std::fs::read("files/dice.qoi").unwrap();
+        let file2 = std::fs::read("files/dice2.qoi").unwrap();
+        assert!(file1 == file2);
     }
 
     #[test]
     fn dice_output_rgba() {
-        let dice =
-            QOIImage::from_qoi_file(BufReader::new(File::open("files/dice.qoi").unwrap()).bytes())
-                .unwrap();
+        let dice = QOIImage::from_reader(File::open("files/dice.qoi").unwrap()).unwrap();
 
         let img = &mut dice
             .to_rgba_mat()
             .iter()
             .flatten()
-            .map(|x| vec![x.0, x.1, x.2, x.3])
-            .flatten()
+            .flat_map(|x| vec![x.r, x.g, x.b, x.a])
             .collect::<Vec<u8>>();
         std::fs::write("files/dice.rgba", img).unwrap();
 
-        let file1 = BufReader::new(File::open("files/dice.rgba").unwrap()).bytes();
-        let file2 = BufReader::new(File::open("files/dice2.rgba").unwrap()).bytes();
-        for (b1, b2) in file1.zip(file2) {
-            assert!(b1.unwrap() == b2.unwrap());
-        }
+        let file1 = std::fs::read("files/dice.rgba").unwrap();
+        let file2 = std::fs::read("files/dice2.rgba").unwrap();
+        assert_eq!(file1.len(), 800 * 600 * 4);
+        assert!(file1 == file2);
     }
 
     #[test]
@@ -588,7 +1971,7 @@ mod tests {
             buf[i] = byte;
             i += 1;
             if i == 4 {
-                imgdata.push(PixelRGBA(buf[0], buf[1], buf[2], buf[3]));
+                imgdata.push(PixelRGBA::new(buf[0], buf[1], buf[2], buf[3]));
                 i = 0;
             }
         }
@@ -609,15 +1992,1037 @@ mod tests {
             .to_rgba_mat()
             .iter()
             .flatten()
-            .map(|x| vec![x.0, x.1, x.2, x.3])
-            .flatten()
+            .flat_map(|x| vec![x.r, x.g, x.b, x.a])
             .collect::<Vec<u8>>();
         std::fs::write("files/testcard_rgba_output.rgba", testcard).unwrap();
 
-        let file1 = BufReader::new(File::open("files/testcard_rgba_output.rgba").unwrap()).bytes();
-        let file2 = BufReader::new(File::open("files/testcard_rgba.rgba").unwrap()).bytes();
-        for (b1, b2) in file1.zip(file2) {
-            assert!(b1.unwrap() == b2.unwrap());
+        let file1 = std::fs::read("files/testcard_rgba_output.rgba").unwrap();
+        let file2 = std::fs::read("files/testcard_rgba.rgba").unwrap();
+        assert_eq!(file1.len(), 256 * 256 * 4);
+        assert!(file1 == file2);
+    }
+
+    fn header_bytes(channels: u8, color_space: u8) -> Vec<u8> {
+        let mut bytes = b"qoif".to_vec();
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.push(channels);
+        bytes.push(color_space);
+        bytes
+    }
+
+    #[test]
+    fn error_truncated_header() {
+        let res = QOIImage::from_reader(b"qoif\0\0".as_slice());
+        assert!(matches!(res, Err(QoiError::TruncatedHeader { len: 6 })));
+    }
+
+    #[test]
+    fn error_bad_header_fields() {
+        let mut bytes = header_bytes(4, 0);
+        bytes[0] = b'Q';
+        let res = QOIImage::from_reader(bytes.as_slice());
+        assert!(matches!(res, Err(QoiError::BadMagic { found }) if &found == b"Qoif"));
+
+        let bytes = header_bytes(5, 0);
+        let res = QOIImage::from_reader(bytes.as_slice());
+        assert!(matches!(res, Err(QoiError::BadChannels { value: 5 })));
+
+        let bytes = header_bytes(4, 2);
+        let res = QOIImage::from_reader(bytes.as_slice());
+        assert!(matches!(res, Err(QoiError::BadColorSpace { value: 2 })));
+    }
+
+    #[test]
+    fn error_truncated_chunk_and_missing_end_marker() {
+        let mut bytes = header_bytes(4, 0);
+        bytes.extend_from_slice(&[0b11111111, 1, 2]);
+        let res = QOIImage::from_reader(bytes.as_slice());
+        assert!(matches!(res, Err(QoiError::TruncatedChunk { offset: 14 })));
+
+        let mut bytes = header_bytes(4, 0);
+        bytes.extend_from_slice(&[0b11000000, 0, 0, 0]);
+        let res = QOIImage::from_reader(bytes.as_slice());
+        assert!(matches!(
+            res,
+            Err(QoiError::MissingEndMarker { offset: 18 })
+        ));
+    }
+
+    /// Hands out at most three bytes per read, interrupting every other call,
+    /// and fails once `fail_at` bytes have gone.
+    struct Flaky<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+        fail_at: usize,
+    }
+
+    impl Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if !self.interrupt {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            if self.fail_at == 0 {
+                return Err(std::io::Error::other("disk on fire"));
+            }
+            let n = buf.len().min(self.data.len()).min(self.fail_at).min(3);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.fail_at -= n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn reader_errors_are_not_truncation() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let flaky = |fail_at| Flaky {
+            data: &dice,
+            interrupt: false,
+            fail_at,
+        };
+
+        let img = QOIImage::from_reader(flaky(usize::MAX)).unwrap();
+        assert_eq!(img.serialize(), dice);
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        QOIImage::from_reader_with_options(flaky(usize::MAX), &strict).unwrap();
+
+        for fail_at in [5, 20, dice.len() / 2, dice.len() - 1] {
+            let res = QOIImage::from_reader(flaky(fail_at));
+            assert!(
+                matches!(&res, Err(QoiError::Io(e)) if e.to_string() == "disk on fire"),
+                "failing at {fail_at}"
+            );
+            let res = QOIImage::from_reader_recovering(flaky(fail_at), PixelRGBA::default());
+            match res {
+                Ok((_, report)) => assert!(matches!(report.error, Some(QoiError::Io(_)))),
+                Err(e) => assert!(fail_at < 14 && matches!(e, QoiError::Io(_))),
+            }
+        }
+        // strict mode reads on past the end marker to check for trailing data
+        let res = QOIImage::from_reader_with_options(flaky(dice.len()), &strict);
+        assert!(matches!(res, Err(QoiError::Io(_))));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn from_qoi_file_still_reads_bytes() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let img = QOIImage::from_qoi_file(dice.as_slice().bytes()).unwrap();
+        assert_eq!(img.serialize(), dice);
+        let res = QOIImage::from_qoi_file(dice[..dice.len() - 1].bytes());
+        assert!(matches!(res, Err(QoiError::MissingEndMarker { .. })));
+    }
+
+    #[test]
+    fn dice_accessors() {
+        let dice = QOIImage::from_reader(File::open("files/dice.qoi").unwrap()).unwrap();
+        assert_eq!(dice.width(), 800);
+        assert_eq!(dice.height(), 600);
+        assert_eq!(dice.channels(), Channels::RGBA);
+        assert_eq!(dice.color_space(), ColorSpace::SRGB);
+        assert!(dice.chunk_count() > 0);
+    }
+
+    #[test]
+    fn pixel_conversions() {
+        let px = PixelRGBA::from(0x11223344u32);
+        assert_eq!(px, PixelRGBA::new(0x11, 0x22, 0x33, 0x44));
+        assert_eq!(px, PixelRGBA::from([0x11, 0x22, 0x33, 0x44]));
+        assert_eq!(u32::from(px), 0x11223344);
+        assert_eq!(<[u8; 4]>::from(px), [0x11, 0x22, 0x33, 0x44]);
+
+        let rgb = PixelRGB::from(0xAA112233u32);
+        assert_eq!(rgb, PixelRGB::new(0x11, 0x22, 0x33));
+        assert_eq!(PixelRGBA::from(rgb), PixelRGBA::new(0x11, 0x22, 0x33, 255));
+        assert_eq!(PixelRGB::from(px), rgb);
+    }
+
+    #[test]
+    fn dice_decode_to_vec() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let (header, pixels) = decode_to_vec(&dice).unwrap();
+        assert_eq!(header.width, 800);
+        assert_eq!(header.height, 600);
+        assert_eq!(header.channels, Channels::RGBA);
+        assert!(pixels == std::fs::read("files/dice2.rgba").unwrap());
+    }
+
+    #[test]
+    fn testcard_encode_to_vec_back_to_vec() {
+        let testcard = std::fs::read("files/testcard_rgba.rgba").unwrap();
+        let header = Header {
+            width: 256,
+            height: 256,
+            channels: Channels::RGBA,
+            color_space: ColorSpace::SRGB,
+        };
+        let encoded = encode_to_vec(&testcard, header).unwrap();
+        assert_eq!(decode_to_vec(&encoded).unwrap(), (header, testcard.clone()));
+
+        // dropping the alpha channel goes through the 3 channel path
+        let rgb: Vec<u8> = testcard
+            .chunks_exact(4)
+            .flat_map(|px| px[..3].to_vec())
+            .collect();
+        let header = Header {
+            channels: Channels::RGB,
+            ..header
+        };
+        let encoded = encode_to_vec(&rgb, header).unwrap();
+        assert_eq!(decode_to_vec(&encoded).unwrap(), (header, rgb));
+    }
+
+    #[test]
+    fn decode_to_vec_errors() {
+        let mut bytes = header_bytes(4, 0);
+        bytes.extend_from_slice(&QOI_END_MARKER);
+        assert!(matches!(
+            decode_to_vec(&bytes),
+            Err(QoiError::PixelCountMismatch {
+                expected: 1,
+                actual: 0
+            })
+        ));
+
+        let mut bytes = header_bytes(4, 0);
+        bytes.push(0b11000000);
+        assert!(matches!(
+            decode_to_vec(&bytes),
+            Err(QoiError::MissingEndMarker { offset: 15 })
+        ));
+
+        let header = Header {
+            width: 2,
+            height: 2,
+            channels: Channels::RGB,
+            color_space: ColorSpace::Linear,
+        };
+        assert!(matches!(
+            encode_to_vec(&[0; 11], header),
+            Err(QoiError::BadInputLength {
+                expected: 12,
+                actual: 11
+            })
+        ));
+    }
+
+    fn solid_mat(px: PixelRGBA, width: usize, height: usize) -> Vec<Vec<PixelRGBA>> {
+        vec![vec![px; width]; height]
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        for (width, height) in [(61, 1), (62, 1), (63, 1), (124, 1), (256, 1), (300, 1)] {
+            // the first pixel matches the initial previous pixel, so the whole image is a run
+            let mat = solid_mat(PixelRGBA::new(0, 0, 0, 255), width, height);
+            let img = QOIImage::from_rgba_mat(&mat, width, height);
+            assert_eq!(img.chunk_count(), width.div_ceil(62));
+            for chunk in &img.data {
+                assert!(matches!(chunk, Chunk::Run(Run(n)) if *n < 62));
+            }
+        }
+    }
+
+    #[test]
+    fn large_solid_images_round_trip() {
+        for (px, width, height) in [
+            (PixelRGBA::new(0, 0, 0, 255), 1000, 1000),
+            (PixelRGBA::new(255, 255, 255, 255), 1920, 1080),
+            (PixelRGBA::new(30, 144, 255, 255), 640, 480),
+            (PixelRGBA::new(12, 34, 56, 0), 333, 777),
+        ] {
+            let mat = solid_mat(px, width, height);
+            let bytes = QOIImage::from_rgba_mat(&mat, width, height).serialize();
+
+            let img = QOIImage::from_reader(bytes.as_slice()).unwrap();
+            assert!(img.to_rgba_mat() == mat);
+
+            let (_, pixels) = decode_to_vec(&bytes).unwrap();
+            assert_eq!(pixels.len(), width * height * img.channels().count());
+        }
+    }
+
+    #[test]
+    fn encode_options_set_header() {
+        let opaque = solid_mat(PixelRGBA::new(1, 2, 3, 255), 4, 4);
+
+        let img = QOIImage::from_rgba_mat(&opaque, 4, 4);
+        assert_eq!(img.channels(), Channels::RGB);
+        assert_eq!(img.color_space(), ColorSpace::Linear);
+
+        let options = EncodeOptions {
+            channels: Some(Channels::RGBA),
+            color_space: ColorSpace::SRGB,
+            ..EncodeOptions::default()
+        };
+        let bytes = QOIImage::from_rgba_mat_with_options(&opaque, 4, 4, &options)
+            .unwrap()
+            .serialize();
+        assert_eq!(bytes[12..14], [4, 0]);
+        let (header, pixels) = decode_to_vec(&bytes).unwrap();
+        assert_eq!(header.channels, Channels::RGBA);
+        assert_eq!(header.color_space, ColorSpace::SRGB);
+        assert_eq!(pixels, [1, 2, 3, 255].repeat(16));
+
+        let transparent = solid_mat(PixelRGBA::new(1, 2, 3, 4), 4, 4);
+        let options = EncodeOptions {
+            channels: Some(Channels::RGB),
+            ..EncodeOptions::default()
+        };
+        let bytes = QOIImage::from_rgba_mat_with_options(&transparent, 4, 4, &options)
+            .unwrap()
+            .serialize();
+        assert_eq!(bytes[12..14], [3, 1]);
+        let (_, pixels) = decode_to_vec(&bytes).unwrap();
+        assert_eq!(pixels, [1, 2, 3].repeat(16));
+    }
+
+    fn stream(width: u32, height: u32, chunks: &[u8]) -> Vec<u8> {
+        let mut bytes = b"qoif".to_vec();
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&height.to_be_bytes());
+        bytes.extend_from_slice(&[4, 0]);
+        bytes.extend_from_slice(chunks);
+        bytes
+    }
+
+    #[test]
+    fn strict_decoding() {
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        QOIImage::from_reader_with_options(dice.as_slice(), &strict).unwrap();
+        decode_to_vec_with_options(&dice, &strict).unwrap();
+
+        // an end-marker-like pattern in the middle of the chunks is just pixels
+        let mut chunks = vec![0, 0, 0, 0, 0, 0, 0, 1, 0];
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        let bytes = stream(9, 1, &chunks);
+        let img = QOIImage::from_reader_with_options(bytes.as_slice(), &strict).unwrap();
+        assert_eq!(img.chunk_count(), 9);
+        let img = QOIImage::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(img.chunk_count(), 9);
+    }
+
+    #[test]
+    fn strict_decoding_errors() {
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+
+        let mut chunks = vec![0b11111111, 1, 2, 3, 4];
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        let bytes = stream(20, 1, &chunks);
+        let res = QOIImage::from_reader_with_options(bytes.as_slice(), &strict);
+        assert!(matches!(
+            res,
+            Err(QoiError::PixelCountMismatch {
+                expected: 20,
+                actual: 1
+            })
+        ));
+        let res = QOIImage::from_reader(bytes.as_slice());
+        assert!(matches!(
+            res,
+            Err(QoiError::PixelCountMismatch {
+                expected: 20,
+                actual: 1
+            })
+        ));
+
+        // with only a pixel or two missing, the end marker itself decodes as
+        // index chunks, so the marker is missed rather than the pixels
+        let bytes = stream(2, 1, &chunks);
+        let res = QOIImage::from_reader_with_options(bytes.as_slice(), &strict);
+        assert!(matches!(
+            res,
+            Err(QoiError::MissingEndMarker { offset: 20 })
+        ));
+
+        let mut chunks = vec![0b11000001];
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        let bytes = stream(1, 1, &chunks);
+        let res = QOIImage::from_reader_with_options(bytes.as_slice(), &strict);
+        assert!(matches!(res, Err(QoiError::RunOverflow { offset: 14 })));
+        let res = decode_to_vec_with_options(&bytes, &strict);
+        assert!(matches!(res, Err(QoiError::RunOverflow { offset: 14 })));
+
+        let mut chunks = vec![0b11000000];
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        chunks.push(0);
+        let bytes = stream(1, 1, &chunks);
+        let res = QOIImage::from_reader_with_options(bytes.as_slice(), &strict);
+        assert!(matches!(res, Err(QoiError::TrailingData { offset: 23 })));
+        let res = decode_to_vec_with_options(&bytes, &strict);
+        assert!(matches!(res, Err(QoiError::TrailingData { offset: 23 })));
+        QOIImage::from_reader(bytes.as_slice()).unwrap();
+        decode_to_vec(&bytes).unwrap();
+
+        let bytes = stream(1, 1, &[0b11000000, 0, 0, 0]);
+        let res = QOIImage::from_reader_with_options(bytes.as_slice(), &strict);
+        assert!(matches!(
+            res,
+            Err(QoiError::MissingEndMarker { offset: 15 })
+        ));
+    }
+
+    #[test]
+    fn recover_truncated_dice() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let expected = std::fs::read("files/dice2.rgba").unwrap();
+        let fill = PixelRGBA::new(255, 0, 255, 255);
+
+        let (_, pixels, report) = decode_to_vec_recovering(&dice, fill).unwrap();
+        assert!(report.error.is_none());
+        assert_eq!(report.pixels_filled, 0);
+        assert!(pixels == expected);
+
+        let truncated = &dice[..dice.len() / 2];
+        let (header, pixels, report) = decode_to_vec_recovering(truncated, fill).unwrap();
+        assert!(matches!(
+            report.error,
+            Some(QoiError::MissingEndMarker { .. } | QoiError::TruncatedChunk { .. })
+        ));
+        assert!(report.pixels_recovered > 0 && report.pixels_filled > 0);
+        assert_eq!(
+            report.pixels_recovered + report.pixels_filled,
+            header.pixel_count()
+        );
+        let split = report.pixels_recovered as usize * 4;
+        assert!(pixels[..split] == expected[..split]);
+        assert!(pixels[split..].chunks(4).all(|px| px == [255, 0, 255, 255]));
+
+        let (img, img_report) = QOIImage::from_reader_recovering(truncated, fill).unwrap();
+        assert_eq!(img_report.pixels_recovered, report.pixels_recovered);
+        assert_eq!(img_report.pixels_filled, report.pixels_filled);
+        let mat: Vec<u8> = img
+            .to_rgba_mat()
+            .iter()
+            .flatten()
+            .flat_map(|x| [x.r, x.g, x.b, x.a])
+            .collect();
+        assert!(mat == pixels);
+    }
+
+    #[test]
+    fn recover_broken_chunk() {
+        // the second RGBA chunk is cut short
+        let bytes = stream(3, 1, &[0b11111111, 1, 2, 3, 4, 0b11111111, 5]);
+        let fill = PixelRGBA::new(9, 9, 9, 9);
+        let (_, pixels, report) = decode_to_vec_recovering(&bytes, fill).unwrap();
+        assert!(matches!(
+            report.error,
+            Some(QoiError::TruncatedChunk { offset: 19 })
+        ));
+        assert_eq!(report.error.unwrap().offset(), Some(19));
+        assert_eq!(pixels, [1, 2, 3, 4, 9, 9, 9, 9, 9, 9, 9, 9]);
+
+        let (img, report) = QOIImage::from_reader_recovering(bytes.as_slice(), fill).unwrap();
+        assert_eq!((report.pixels_recovered, report.pixels_filled), (1, 2));
+        assert_eq!(
+            img.to_rgba_mat(),
+            [[
+                PixelRGBA::new(1, 2, 3, 4),
+                PixelRGBA::new(9, 9, 9, 9),
+                PixelRGBA::new(9, 9, 9, 9)
+            ]]
+        );
+    }
+
+    #[test]
+    fn slice_decoder_agrees_with_chunks() {
+        // arbitrary streams, so chunks an encoder would never write turn up:
+        // index hits on empty slots, runs straight after the header, and so on
+        let mut seed = 1u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        for _ in 0..200 {
+            let mut chunks: Vec<u8> = (0..120).map(|_| next()).collect();
+            chunks.extend_from_slice(&QOI_END_MARKER);
+            let bytes = stream(10, 6, &chunks);
+            let fill = PixelRGBA::new(1, 2, 3, 4);
+
+            let (_, pixels, report) = decode_to_vec_recovering(&bytes, fill).unwrap();
+            let (img, img_report) =
+                QOIImage::from_reader_recovering(bytes.as_slice(), fill).unwrap();
+            assert_eq!(report.pixels_recovered, img_report.pixels_recovered);
+            let mat: Vec<u8> = img
+                .to_rgba_mat()
+                .iter()
+                .flatten()
+                .flat_map(|&px| <[u8; 4]>::from(px))
+                .collect();
+            assert_eq!(mat, pixels);
+
+            let decoded = decode_to_vec(&bytes);
+            let img = QOIImage::from_reader(bytes.as_slice());
+            assert_eq!(decoded.is_ok(), img.is_ok());
+            if let (Ok((_, pixels)), Ok(img)) = (decoded, img) {
+                let mat: Vec<u8> = img
+                    .to_rgba_mat()
+                    .iter()
+                    .flatten()
+                    .flat_map(|&px| <[u8; 4]>::from(px))
+                    .collect();
+                assert_eq!(mat, pixels);
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_decoders_agree() {
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        // the packed pixels from the slice decoder, the chunk reader and, when
+        // lenient, the stream decoder
+        let decoded = |bytes: &[u8], options: &DecodeOptions| {
+            let mut res = vec![
+                decode_to_vec_with_options(bytes, options).map(|(_, pixels)| pixels),
+                QOIImage::from_reader_with_options(bytes, options).map(|img| {
+                    img.to_rgba_mat()
+                        .iter()
+                        .flatten()
+                        .flat_map(|&px| <[u8; 4]>::from(px))
+                        .collect()
+                }),
+            ];
+            if !options.strict {
+                res.push(
+                    StreamDecoder::new(bytes)
+                        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                        .map(|rows| rows.concat()),
+                );
+            }
+            res
+        };
+
+        // two pixels, then chunks for five more before the end marker
+        let mut chunks = vec![0xff, 1, 2, 3, 4, 0xfe, 5, 6, 7];
+        chunks.extend_from_slice(&[0x6a, 0xc1, 0xfe, 8, 9, 10, 0xa0, 0x88]);
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        let bytes = stream(2, 1, &chunks);
+        for res in decoded(&bytes, &DecodeOptions::default()) {
+            assert_eq!(res.unwrap(), [1, 2, 3, 4, 5, 6, 7, 4]);
+        }
+        for res in decoded(&bytes, &strict) {
+            assert!(matches!(
+                res,
+                Err(QoiError::MissingEndMarker { offset: 23 })
+            ));
+        }
+
+        // before the last pixel an end marker is only chunks, so the first
+        // marker here is an index hit and six more chunks to drop
+        let mut chunks = vec![0xff, 1, 2, 3, 4];
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        chunks.extend_from_slice(&[0xc0]);
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        let bytes = stream(2, 1, &chunks);
+        for res in decoded(&bytes, &DecodeOptions::default()) {
+            assert_eq!(res.unwrap(), [1, 2, 3, 4, 0, 0, 0, 0]);
+        }
+
+        // and a stream that ends short is short in all of them
+        let mut chunks = vec![0xff, 1, 2, 3, 4];
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        let bytes = stream(10, 1, &chunks);
+        let res = decoded(&bytes, &DecodeOptions::default());
+        assert!(matches!(
+            res[0],
+            Err(QoiError::PixelCountMismatch { actual: 1, .. })
+        ));
+        assert!(matches!(
+            res[1],
+            Err(QoiError::PixelCountMismatch { actual: 1, .. })
+        ));
+        assert!(res[2].is_err());
+    }
+
+    #[test]
+    fn decode_into_layouts() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let expected = std::fs::read("files/dice2.rgba").unwrap();
+
+        // BGRA with 16 bytes of padding per row, which must be left alone
+        let layout = Layout {
+            order: ChannelOrder::BGRA,
+            stride: 800 * 4 + 16,
+        };
+        let mut buf = vec![0xAB; layout.required_len(800, 600) as usize];
+        decode_into(&dice, &mut buf, layout).unwrap();
+        for (y, row) in expected.chunks(800 * 4).enumerate() {
+            let out = &buf[y * layout.stride..y * layout.stride + 800 * 4];
+            for (px, px_out) in row.chunks(4).zip(out.chunks(4)) {
+                assert_eq!(px_out, [px[2], px[1], px[0], px[3]]);
+            }
+            if y < 599 {
+                assert!(buf[y * layout.stride + 800 * 4..(y + 1) * layout.stride]
+                    .iter()
+                    .all(|&b| b == 0xAB));
+            }
+        }
+
+        let img = QOIImage::from_reader(dice.as_slice()).unwrap();
+        for order in [ChannelOrder::ARGB, ChannelOrder::RGB, ChannelOrder::BGR] {
+            let layout = Layout::packed(order, 800);
+            let mut buf = vec![0; layout.required_len(800, 600) as usize];
+            img.decode_into(&mut buf, layout).unwrap();
+            for (px, px_out) in expected.chunks(4).zip(buf.chunks(order.bytes_per_pixel())) {
+                let want = match order {
+                    ChannelOrder::ARGB => vec![px[3], px[0], px[1], px[2]],
+                    ChannelOrder::RGB => vec![px[0], px[1], px[2]],
+                    _ => vec![px[2], px[1], px[0]],
+                };
+                assert_eq!(px_out, want);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_into_rejects_bad_buffers() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let layout = Layout::packed(ChannelOrder::RGBA, 800);
+        let mut buf = vec![0; 800 * 600 * 4 - 1];
+        assert!(matches!(
+            decode_into(&dice, &mut buf, layout),
+            Err(QoiError::OutputTooSmall {
+                required: 1920000,
+                actual: 1919999
+            })
+        ));
+        assert!(buf.iter().all(|&b| b == 0));
+
+        let layout = Layout {
+            order: ChannelOrder::RGB,
+            stride: 800 * 3 - 1,
+        };
+        assert!(matches!(
+            decode_into(&dice, &mut buf, layout),
+            Err(QoiError::BadStride {
+                stride: 2399,
+                row_len: 2400
+            })
+        ));
+    }
+
+    #[test]
+    fn rgb_input_paths_agree() {
+        let rgb: Vec<u8> = std::fs::read("files/testcard_rgba.rgba")
+            .unwrap()
+            .chunks_exact(4)
+            .flat_map(|px| [px[0], px[1], px[2]])
+            .collect();
+        let header = Header {
+            width: 256,
+            height: 256,
+            channels: Channels::RGB,
+            color_space: ColorSpace::SRGB,
+        };
+
+        let packed = QOIImage::from_packed(&rgb, header).unwrap().serialize();
+        assert_eq!(packed[12], 3);
+        assert!(packed == encode_to_vec(&rgb, header).unwrap());
+        assert_eq!(decode_to_vec(&packed).unwrap(), (header, rgb.clone()));
+
+        let mat: Vec<Vec<PixelRGB>> = rgb
+            .chunks_exact(256 * 3)
+            .map(|row| {
+                row.chunks_exact(3)
+                    .map(|px| PixelRGB::new(px[0], px[1], px[2]))
+                    .collect()
+            })
+            .collect();
+        let options = EncodeOptions {
+            color_space: ColorSpace::SRGB,
+            ..EncodeOptions::default()
+        };
+        let from_mat = QOIImage::from_rgb_mat_with_options(&mat, 256, 256, &options).unwrap();
+        assert_eq!(from_mat.header(), header);
+        assert!(from_mat.serialize() == packed);
+
+        assert!(matches!(
+            QOIImage::from_packed(&rgb[1..], header),
+            Err(QoiError::BadInputLength { .. })
+        ));
+    }
+
+    #[test]
+    fn read_header_only() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let mut cursor = std::io::Cursor::new(&dice);
+        let header = read_header(&mut cursor).unwrap();
+        assert_eq!(cursor.position(), 14);
+        assert_eq!(
+            header,
+            Header {
+                width: 800,
+                height: 600,
+                channels: Channels::RGBA,
+                color_space: ColorSpace::SRGB,
+            }
+        );
+        assert_eq!(header.to_bytes(), dice[..14]);
+        assert_eq!(Header::from_bytes(&header.to_bytes()).unwrap(), header);
+
+        let file = File::open("files/dice.qoi").unwrap();
+        assert_eq!(read_header(file).unwrap(), header);
+
+        assert!(matches!(
+            read_header(&dice[..9]),
+            Err(QoiError::TruncatedHeader { len: 9 })
+        ));
+    }
+
+    #[test]
+    fn limits_stop_decompression_bombs() {
+        let mut bomb = stream(1_000_000, 1_000_000, &[0b11111101]);
+        bomb.extend_from_slice(&QOI_END_MARKER);
+        let is_pixel_limit = |e: QoiError| matches!(e, QoiError::LimitExceeded { kind: LimitKind::Pixels, max, .. } if max == 400_000_000);
+
+        assert!(is_pixel_limit(decode_to_vec(&bomb).unwrap_err()));
+        assert!(is_pixel_limit(
+            QOIImage::from_reader(bomb.as_slice()).err().unwrap()
+        ));
+        assert!(is_pixel_limit(
+            decode_to_vec_recovering(&bomb, PixelRGBA::default()).unwrap_err()
+        ));
+        assert!(is_pixel_limit(
+            StreamDecoder::new(bomb.as_slice()).err().unwrap()
+        ));
+
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        for (limits, kind) in [
+            (
+                Limits {
+                    max_width: 799,
+                    ..Limits::default()
+                },
+                LimitKind::Width,
+            ),
+            (
+                Limits {
+                    max_height: 599,
+                    ..Limits::default()
+                },
+                LimitKind::Height,
+            ),
+            (
+                Limits {
+                    max_bytes: 800 * 600 * 4 - 1,
+                    ..Limits::default()
+                },
+                LimitKind::Bytes,
+            ),
+        ] {
+            let options = DecodeOptions {
+                limits,
+                ..DecodeOptions::default()
+            };
+            let res = decode_to_vec_with_options(&dice, &options);
+            assert!(matches!(res, Err(QoiError::LimitExceeded { kind: k, .. }) if k == kind));
+        }
+
+        // no pixels, but a row for every one of four billion lines
+        let mut empty = stream(0, u32::MAX, &[]);
+        empty.extend_from_slice(&QOI_END_MARKER);
+        let is_height_limit = |e: QoiError| matches!(e, QoiError::LimitExceeded { kind: LimitKind::Height, max, .. } if max == 1_000_000);
+        assert!(is_height_limit(decode_to_vec(&empty).unwrap_err()));
+        assert!(is_height_limit(
+            QOIImage::from_reader(empty.as_slice()).err().unwrap()
+        ));
+        let unlimited = DecodeOptions {
+            limits: Limits::none(),
+            ..DecodeOptions::default()
+        };
+        let img = QOIImage::from_reader_with_options(empty.as_slice(), &unlimited).unwrap();
+        assert!(img.to_rgba_mat().is_empty());
+    }
+
+    #[test]
+    fn hostile_input_is_an_error() {
+        let unlimited = DecodeOptions {
+            limits: Limits::none(),
+            ..DecodeOptions::default()
+        };
+        let mut bomb = stream(u32::MAX, u32::MAX, &[0b11111101]);
+        bomb.extend_from_slice(&QOI_END_MARKER);
+        assert!(matches!(
+            decode_to_vec_with_options(&bomb, &unlimited),
+            Err(QoiError::PixelCountMismatch { actual: 62, .. })
+        ));
+        let res = QOIImage::from_reader_with_options(bomb.as_slice(), &unlimited);
+        assert!(matches!(
+            res,
+            Err(QoiError::PixelCountMismatch { actual: 62, .. })
+        ));
+
+        // no pixels at all, in four billion rows
+        let mut empty = stream(0, u32::MAX, &[]);
+        empty.extend_from_slice(&QOI_END_MARKER);
+        assert!(decode_to_vec(&empty).is_err());
+        assert!(QOIImage::from_reader(empty.as_slice()).is_err());
+        assert!(decode_to_vec_recovering(&empty, PixelRGBA::default()).is_err());
+        assert!(StreamDecoder::new(empty.as_slice()).is_err());
+        let (_, pixels) = decode_to_vec_with_options(&empty, &unlimited).unwrap();
+        assert!(pixels.is_empty());
+        let img = QOIImage::from_reader_with_options(empty.as_slice(), &unlimited).unwrap();
+        assert!(img.try_to_rgba_mat().unwrap().is_empty());
+
+        // a run past the last pixel is cut short
+        let mut chunks = vec![0b11111111, 1, 2, 3, 4, 0b11111101];
+        chunks.extend_from_slice(&QOI_END_MARKER);
+        let img = QOIImage::from_reader(stream(2, 2, &chunks).as_slice()).unwrap();
+        assert_eq!(
+            img.to_rgba_mat(),
+            vec![vec![PixelRGBA::new(1, 2, 3, 4); 2]; 2]
+        );
+
+        // every prefix, and every single-byte change, of a small file
+        let pixels: Vec<u8> = (0..12 * 5 * 4).map(|i| (i * i / 7) as u8).collect();
+        let header = Header {
+            width: 12,
+            height: 5,
+            channels: Channels::RGBA,
+            color_space: ColorSpace::SRGB,
+        };
+        let file = encode_to_vec(&pixels, header).unwrap();
+        for len in 0..file.len() {
+            assert!(decode_to_vec(&file[..len]).is_err());
+            assert!(QOIImage::from_reader(&file[..len]).is_err());
+        }
+        for i in 14..file.len() {
+            for value in [0x00, 0x01, 0x7f, 0xc0, 0xfd, 0xfe, 0xff] {
+                let mut bad = file.clone();
+                bad[i] = value;
+                let _ = decode_to_vec(&bad);
+                if let Ok(img) = QOIImage::from_reader(bad.as_slice()) {
+                    assert_eq!(img.to_rgba_mat().len(), 5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn limits_on_encoding() {
+        let options = EncodeOptions {
+            limits: Limits {
+                max_pixels: 15,
+                ..Limits::none()
+            },
+            ..EncodeOptions::default()
+        };
+        let mat = solid_mat(PixelRGBA::new(1, 2, 3, 255), 4, 4);
+        assert!(matches!(
+            QOIImage::from_rgba_mat_with_options(&mat, 4, 4, &options),
+            Err(QoiError::LimitExceeded {
+                kind: LimitKind::Pixels,
+                value: 16,
+                max: 15
+            })
+        ));
+        QOIImage::from_rgba_mat_with_options(&mat[..3], 4, 3, &options).unwrap();
+
+        let header = Header {
+            width: 4,
+            height: 4,
+            channels: Channels::RGB,
+            color_space: ColorSpace::SRGB,
+        };
+        assert!(matches!(
+            StreamEncoder::with_limits(Vec::new(), header, options.limits),
+            Err(QoiError::LimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn reference_compatible_encoding() {
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        // written by the reference encoder from the pixels they decode to
+        for name in [
+            "dice.qoi",
+            "reference/alpha.qoi",
+            "reference/noise.qoi",
+            "reference/runs.qoi",
+            "reference/testcard.qoi",
+            "reference/tiny.qoi",
+            "reference/wrap.qoi",
+        ] {
+            let expected = std::fs::read(format!("files/{name}")).unwrap();
+            let (header, pixels) = decode_to_vec_with_options(&expected, &strict).unwrap();
+            let encoded =
+                encode_to_vec_with_mode(&pixels, header, EncodeMode::ReferenceCompatible).unwrap();
+            assert!(encoded == expected, "{name} differs");
+
+            let mat = QOIImage::from_reader(expected.as_slice())
+                .unwrap()
+                .to_rgba_mat();
+            let options = EncodeOptions {
+                channels: Some(header.channels),
+                color_space: header.color_space,
+                mode: EncodeMode::ReferenceCompatible,
+                ..EncodeOptions::default()
+            };
+            let width = header.width as usize;
+            let height = header.height as usize;
+            let image = QOIImage::from_rgba_mat_with_options(&mat, width, height, &options);
+            assert!(image.unwrap().serialize() == expected, "{name} differs");
+            let image =
+                QOIImage::from_packed_with_mode(&pixels, header, EncodeMode::ReferenceCompatible);
+            assert!(image.unwrap().serialize() == expected, "{name} differs");
+        }
+
+        // steps across 255 -> 0 are where the default mode parts ways
+        let wrap = std::fs::read("files/reference/wrap.qoi").unwrap();
+        let (header, pixels) = decode_to_vec(&wrap).unwrap();
+        let standard = encode_to_vec(&pixels, header).unwrap();
+        assert!(standard.len() > wrap.len());
+        assert_eq!(decode_to_vec(&standard).unwrap().1, pixels);
+    }
+
+    /// Pixels of every kind the encoder treats differently: noise, smooth
+    /// gradients, gradients with flickering alpha, a handful of colours, and
+    /// runs long enough to need several run chunks.
+    fn pixels(count: usize) -> impl proptest::strategy::Strategy<Value = Vec<PixelRGBA>> {
+        use proptest::{collection::vec, prelude::*, sample::Index};
+
+        let px = || any::<[u8; 4]>().prop_map(PixelRGBA::from);
+        let step = || [-40i8..=40, -40i8..=40, -40i8..=40];
+        let gradient = |start: PixelRGBA, [dr, dg, db]: [i8; 3], i: usize| {
+            let at = |c: u8, d: i8| c.wrapping_add((d as u8).wrapping_mul(i as u8));
+            PixelRGBA::new(at(start.r, dr), at(start.g, dg), at(start.b, db), start.a)
+        };
+        prop_oneof![
+            vec(px(), count),
+            (px(), step(), -2i8..=2).prop_map(move |(start, step, da)| {
+                (0..count)
+                    .map(|i| PixelRGBA {
+                        a: start.a.wrapping_add((da as u8).wrapping_mul(i as u8)),
+                        ..gradient(start, step, i)
+                    })
+                    .collect()
+            }),
+            (
+                px(),
+                step(),
+                vec(prop::sample::select(vec![0, 1, 128, 255]), count)
+            )
+                .prop_map(move |(start, step, alpha)| {
+                    (0..count)
+                        .map(|i| PixelRGBA {
+                            a: alpha[i],
+                            ..gradient(start, step, i)
+                        })
+                        .collect()
+                }),
+            (vec(px(), 1..6), vec(any::<Index>(), count)).prop_map(|(palette, picks)| {
+                picks
+                    .iter()
+                    .map(|i| palette[i.index(palette.len())])
+                    .collect()
+            }),
+            vec((px(), 1usize..=200), 1..6).prop_map(move |runs| {
+                runs.iter()
+                    .flat_map(|&(px, n)| std::iter::repeat_n(px, n))
+                    .cycle()
+                    .take(count)
+                    .collect()
+            }),
+        ]
+    }
+
+    /// A header, mostly small but sometimes a long strip, and packed pixels to
+    /// match it.
+    fn image() -> impl proptest::strategy::Strategy<Value = (Header, Vec<u8>)> {
+        use proptest::prelude::*;
+
+        let dims = prop_oneof![(0u32..=20, 0u32..=20), (1u32..=400, 1u32..=2)];
+        (dims, any::<bool>(), any::<bool>()).prop_flat_map(|((width, height), rgba, linear)| {
+            let header = Header {
+                width,
+                height,
+                channels: if rgba { Channels::RGBA } else { Channels::RGB },
+                color_space: if linear {
+                    ColorSpace::Linear
+                } else {
+                    ColorSpace::SRGB
+                },
+            };
+            pixels((width * height) as usize).prop_map(move |pixels| {
+                let channels = header.channels.count();
+                let mut packed = Vec::with_capacity(pixels.len() * channels);
+                for px in pixels {
+                    packed.extend_from_slice(&<[u8; 4]>::from(px)[..channels]);
+                }
+                (header, packed)
+            })
+        })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn round_trip((header, pixels) in image()) {
+            use proptest::{prop_assert, prop_assert_eq};
+
+            let expected_len = header.pixel_count() as usize * header.channels.count();
+            let strict = DecodeOptions {
+                strict: true,
+                ..DecodeOptions::default()
+            };
+            let encoded = encode_to_vec(&pixels, header).unwrap();
+            let (decoded_header, decoded) = decode_to_vec_with_options(&encoded, &strict).unwrap();
+            prop_assert_eq!(decoded_header, header);
+            prop_assert_eq!(decoded.len(), expected_len);
+            prop_assert_eq!(&decoded, &pixels);
+            let reference =
+                encode_to_vec_with_mode(&pixels, header, EncodeMode::ReferenceCompatible).unwrap();
+            prop_assert_eq!(decode_to_vec(&reference).unwrap().1, pixels.clone());
+
+            let image = QOIImage::from_packed(&pixels, header).unwrap();
+            prop_assert!(image.serialize() == encoded);
+            let image =
+                QOIImage::from_reader_with_options(encoded.as_slice(), &strict).unwrap();
+            let mat = image.to_rgba_mat();
+            let rows = if header.width == 0 { 0 } else { header.height };
+            prop_assert_eq!(mat.len(), rows as usize);
+            prop_assert!(mat.iter().all(|row| row.len() == header.width as usize));
+            let channels = header.channels.count();
+            let mat_rows: Vec<Vec<u8>> = mat
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .flat_map(|&px| <[u8; 4]>::from(px)[..channels].to_vec())
+                        .collect()
+                })
+                .collect();
+            prop_assert_eq!(&mat_rows.concat(), &pixels);
+
+            let rows = StreamDecoder::new(encoded.as_slice())
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            prop_assert_eq!(&rows, &mat_rows);
+            prop_assert_eq!(rows.concat(), pixels);
+
+            // every decoder needs the end marker, even with no rows to read
+            let cut = &encoded[..encoded.len() - 8];
+            let streamed = StreamDecoder::new(cut).and_then(|d| d.collect::<Result<Vec<_>, _>>());
+            prop_assert!(streamed.is_err());
+            prop_assert!(decode_to_vec(cut).is_err());
         }
     }
 }
@@ -0,0 +1,766 @@
+//! PNG import and export, so QOI files can be made from and turned back into
+//! the format most images arrive in.
+//!
+//! Decoding takes greyscale, truecolor, palette, grey+alpha and truecolor+alpha
+//! images at 8 bits per sample (and 1, 2 or 4 bits for greyscale and palette),
+//! interlaced or not. Pixels come out as RGB or RGBA, RGBA whenever the image
+//! has an alpha channel or transparency, in the same layout as
+//! [`decode_to_vec`](crate::decode_to_vec). Encoding writes 8-bit RGB or RGBA.
+
+use std::fmt;
+
+use crate::{check_input_len, Channels, ColorSpace, Header, Limits, QoiError};
+
+mod zlib;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+// IDAT data is split into chunks of at most this many bytes when encoding
+const IDAT_SIZE: usize = 1 << 20;
+// gAMA stores gamma times 100000; 1.0 means linear samples
+const LINEAR_GAMMA: u32 = 100_000;
+
+// (x, y) of the first pixel and the spacing between pixels of each Adam7 pass
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Everything that can go wrong while reading or writing a PNG.
+///
+/// Offsets are byte positions from the start of the file.
+#[derive(Debug)]
+pub enum PngError {
+    /// The first eight bytes were not the PNG signature.
+    BadSignature,
+    /// The file ended in the middle of the chunk starting at `offset`.
+    TruncatedChunk { offset: usize },
+    /// The chunk starting at `offset` failed its CRC check.
+    BadCrc { chunk: [u8; 4], offset: usize },
+    /// A chunk every PNG must have is missing or out of place.
+    MissingChunk { chunk: [u8; 4] },
+    /// The color type and bit depth combination is not one this module reads.
+    Unsupported { color_type: u8, bit_depth: u8 },
+    /// The chunks are well formed but what they hold is not valid PNG.
+    Malformed(&'static str),
+    /// The compressed image data is corrupt.
+    BadDeflate(&'static str),
+    /// PNG has no way to store a zero width or height image.
+    EmptyImage,
+    /// The image is too large for the limits, or a buffer handed to the
+    /// encoder has the wrong length.
+    Image(QoiError),
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |chunk: &[u8; 4]| String::from_utf8_lossy(chunk).into_owned();
+        match self {
+            PngError::BadSignature => write!(f, "Malformed PNG: signature not found"),
+            PngError::TruncatedChunk { offset } => write!(
+                f,
+                "Malformed PNG: reached end of file abruptly in chunk at byte {offset}"
+            ),
+            PngError::BadCrc { chunk, offset } => write!(
+                f,
+                "Malformed PNG: bad CRC in {} chunk at byte {offset}",
+                name(chunk)
+            ),
+            PngError::MissingChunk { chunk } => {
+                write!(f, "Malformed PNG: missing {} chunk", name(chunk))
+            }
+            PngError::Unsupported {
+                color_type,
+                bit_depth,
+            } => write!(
+                f,
+                "Unsupported PNG: color type {color_type} at {bit_depth} bits per sample"
+            ),
+            PngError::Malformed(msg) => write!(f, "Malformed PNG: {msg}"),
+            PngError::BadDeflate(msg) => write!(f, "Malformed PNG: image data {msg}"),
+            PngError::EmptyImage => write!(f, "Invalid input: PNG images cannot be empty"),
+            PngError::Image(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PngError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PngError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<QoiError> for PngError {
+    fn from(e: QoiError) -> Self {
+        PngError::Image(e)
+    }
+}
+
+/// The fields of an IHDR chunk this module looks at.
+struct ImageHeader {
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl ImageHeader {
+    fn samples_per_pixel(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    /// Bytes in one row of `width` pixels, not counting the filter byte.
+    fn row_len(&self, width: usize) -> usize {
+        (width * self.samples_per_pixel() * self.bit_depth as usize).div_ceil(8)
+    }
+
+    /// The distance back to the corresponding byte of the previous pixel, as
+    /// the filters use it; whole bytes, so at least one.
+    fn filter_stride(&self) -> usize {
+        (self.samples_per_pixel() * self.bit_depth as usize / 8).max(1)
+    }
+
+    /// (first x, first y, x step, y step) of each pass over the image.
+    fn passes(&self) -> &'static [(usize, usize, usize, usize)] {
+        if self.interlaced {
+            &ADAM7
+        } else {
+            &[(0, 0, 1, 1)]
+        }
+    }
+}
+
+/// Decodes a PNG into packed RGB or RGBA pixels, with the default
+/// [`Limits`].
+pub fn decode(data: &[u8]) -> Result<(Header, Vec<u8>), PngError> {
+    decode_with_limits(data, Limits::default())
+}
+
+/// Like [`decode`], but rejects images the `limits` do not allow before
+/// decompressing anything.
+pub fn decode_with_limits(data: &[u8], limits: Limits) -> Result<(Header, Vec<u8>), PngError> {
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return Err(PngError::BadSignature);
+    }
+
+    let mut ihdr = None;
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+    let mut color_space = ColorSpace::SRGB;
+    let mut idat = Vec::new();
+    let mut seen_iend = false;
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() && !seen_iend {
+        let (kind, body, len) = read_chunk(data, pos)?;
+        if ihdr.is_none() && &kind != b"IHDR" {
+            return Err(PngError::MissingChunk { chunk: *b"IHDR" });
+        }
+        match &kind {
+            b"IHDR" => ihdr = Some(read_ihdr(body)?),
+            b"PLTE" => palette = body,
+            b"tRNS" => transparency = body,
+            b"gAMA" if body == LINEAR_GAMMA.to_be_bytes() => color_space = ColorSpace::Linear,
+            b"sRGB" => color_space = ColorSpace::SRGB,
+            b"IDAT" => idat.extend_from_slice(body),
+            b"IEND" => seen_iend = true,
+            // ancillary chunks have a lowercase first letter and may be skipped
+            _ if kind[0].is_ascii_uppercase() => {
+                return Err(PngError::Malformed("unknown critical chunk"))
+            }
+            _ => {}
+        }
+        pos += len;
+    }
+    let ihdr = ihdr.ok_or(PngError::MissingChunk { chunk: *b"IHDR" })?;
+    if idat.is_empty() {
+        return Err(PngError::MissingChunk { chunk: *b"IDAT" });
+    }
+    if !seen_iend {
+        return Err(PngError::MissingChunk { chunk: *b"IEND" });
+    }
+    if ihdr.color_type == 3 && (palette.is_empty() || !palette.len().is_multiple_of(3)) {
+        return Err(PngError::Malformed(
+            "palette image without a valid PLTE chunk",
+        ));
+    }
+
+    let has_alpha = matches!(ihdr.color_type, 4 | 6) || !transparency.is_empty();
+    let header = Header {
+        width: ihdr.width as u32,
+        height: ihdr.height as u32,
+        channels: if has_alpha {
+            Channels::RGBA
+        } else {
+            Channels::RGB
+        },
+        color_space,
+    };
+    limits.check(&header)?;
+
+    let filtered_len: usize = ihdr
+        .passes()
+        .iter()
+        .map(|&pass| {
+            let (width, height) = pass_size(&ihdr, pass);
+            if width == 0 {
+                0
+            } else {
+                height * (1 + ihdr.row_len(width))
+            }
+        })
+        .sum();
+    // anything past the filtered rows would only be thrown away, so a stream
+    // that inflates further is refused rather than buffered
+    let raw = zlib::decompress(&idat, filtered_len)?;
+    if raw.len() < filtered_len {
+        return Err(PngError::Malformed("not enough image data"));
+    }
+
+    let channels = header.channels.count();
+    let mut out = vec![0u8; ihdr.width * ihdr.height * channels];
+    let to_rgba = PixelConverter {
+        ihdr: &ihdr,
+        palette,
+        transparency,
+    };
+    let mut raw = &raw[..];
+    for &pass in ihdr.passes() {
+        let (x0, y0, dx, dy) = pass;
+        let (width, height) = pass_size(&ihdr, pass);
+        if width == 0 {
+            continue;
+        }
+        let row_len = ihdr.row_len(width);
+        let mut prev = vec![0u8; row_len];
+        let mut row = vec![0u8; row_len];
+        for py in 0..height {
+            let (filter, rest) = raw.split_at(1);
+            row.copy_from_slice(&rest[..row_len]);
+            raw = &rest[row_len..];
+            unfilter(filter[0], &mut row, &prev, ihdr.filter_stride())?;
+
+            let y = y0 + py * dy;
+            for px in 0..width {
+                let rgba = to_rgba.pixel(&row, px)?;
+                let at = (y * ihdr.width + x0 + px * dx) * channels;
+                out[at..at + channels].copy_from_slice(&rgba[..channels]);
+            }
+            std::mem::swap(&mut row, &mut prev);
+        }
+    }
+    Ok((header, out))
+}
+
+/// Encodes packed pixels, three or four bytes each depending on
+/// `header.channels`, as an 8-bit RGB or RGBA PNG.
+///
+/// A linear `color_space` is recorded with a gamma of 1.0, sRGB with an sRGB
+/// chunk.
+pub fn encode(pixels: &[u8], header: Header) -> Result<Vec<u8>, PngError> {
+    check_input_len(pixels, &header)?;
+    if header.width == 0 || header.height == 0 {
+        return Err(PngError::EmptyImage);
+    }
+
+    let channels = header.channels.count();
+    let row_len = header.width as usize * channels;
+    let mut filtered = Vec::with_capacity(pixels.len() + header.height as usize);
+    let mut prev: &[u8] = &vec![0u8; row_len];
+    let mut candidate = vec![0u8; row_len];
+    let mut best = vec![0u8; row_len];
+    for row in pixels.chunks_exact(row_len) {
+        // the usual heuristic: the filter whose output, read as signed
+        // bytes, has the smallest sum of absolute values
+        let mut best_filter = 0;
+        let mut best_score = u64::MAX;
+        for filter in 0..5 {
+            apply_filter(filter, row, prev, channels, &mut candidate);
+            let score = candidate
+                .iter()
+                .map(|&b| (b as i8).unsigned_abs() as u64)
+                .sum();
+            if score < best_score {
+                (best_filter, best_score) = (filter, score);
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+        filtered.push(best_filter);
+        filtered.extend_from_slice(&best);
+        prev = row;
+    }
+
+    let compressed = zlib::compress(&filtered);
+    let mut res = Vec::with_capacity(compressed.len() + 64);
+    res.extend_from_slice(&PNG_SIGNATURE);
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&header.width.to_be_bytes());
+    ihdr.extend_from_slice(&header.height.to_be_bytes());
+    let color_type = match header.channels {
+        Channels::RGB => 2,
+        Channels::RGBA => 6,
+    };
+    // bit depth, color type, compression, filter method, no interlacing
+    ihdr.extend_from_slice(&[8, color_type, 0, 0, 0]);
+    write_chunk(&mut res, b"IHDR", &ihdr);
+    match header.color_space {
+        // perceptual rendering intent
+        ColorSpace::SRGB => write_chunk(&mut res, b"sRGB", &[0]),
+        ColorSpace::Linear => write_chunk(&mut res, b"gAMA", &LINEAR_GAMMA.to_be_bytes()),
+    }
+    for part in compressed.chunks(IDAT_SIZE) {
+        write_chunk(&mut res, b"IDAT", part);
+    }
+    write_chunk(&mut res, b"IEND", &[]);
+    Ok(res)
+}
+
+/// Reads the chunk at `pos`, returning its type, its data and its length
+/// including the length, type and CRC fields.
+fn read_chunk(data: &[u8], pos: usize) -> Result<([u8; 4], &[u8], usize), PngError> {
+    let truncated = PngError::TruncatedChunk { offset: pos };
+    let fields = data.get(pos..pos + 8).ok_or(truncated)?;
+    let len = u32::from_be_bytes([fields[0], fields[1], fields[2], fields[3]]) as usize;
+    let kind = [fields[4], fields[5], fields[6], fields[7]];
+    let total = len
+        .checked_add(12)
+        .ok_or(PngError::TruncatedChunk { offset: pos })?;
+    let chunk = data
+        .get(pos..pos.saturating_add(total))
+        .ok_or(PngError::TruncatedChunk { offset: pos })?;
+    let (checked, crc) = chunk[4..].split_at(len + 4);
+    if crc32(checked) != u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return Err(PngError::BadCrc {
+            chunk: kind,
+            offset: pos,
+        });
+    }
+    Ok((kind, &checked[4..], total))
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn read_ihdr(body: &[u8]) -> Result<ImageHeader, PngError> {
+    let &[w0, w1, w2, w3, h0, h1, h2, h3, bit_depth, color_type, compression, filter, interlace] =
+        body
+    else {
+        return Err(PngError::Malformed("IHDR chunk has the wrong length"));
+    };
+    let width = u32::from_be_bytes([w0, w1, w2, w3]);
+    let height = u32::from_be_bytes([h0, h1, h2, h3]);
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+        return Err(PngError::Malformed("image dimensions out of range"));
+    }
+    let supported = match color_type {
+        0 | 3 => matches!(bit_depth, 1 | 2 | 4 | 8),
+        2 | 4 | 6 => bit_depth == 8,
+        _ => false,
+    };
+    if !supported {
+        return Err(PngError::Unsupported {
+            color_type,
+            bit_depth,
+        });
+    }
+    if compression != 0 || filter != 0 || interlace > 1 {
+        return Err(PngError::Malformed(
+            "unknown compression, filter or interlace method",
+        ));
+    }
+    Ok(ImageHeader {
+        width: width as usize,
+        height: height as usize,
+        bit_depth,
+        color_type,
+        interlaced: interlace == 1,
+    })
+}
+
+/// The size of the sub-image a pass covers.
+fn pass_size(ihdr: &ImageHeader, (x0, y0, dx, dy): (usize, usize, usize, usize)) -> (usize, usize) {
+    let width = ihdr.width.saturating_sub(x0).div_ceil(dx);
+    let height = ihdr.height.saturating_sub(y0).div_ceil(dy);
+    (width, height)
+}
+
+/// Turns the samples of one pixel into RGBA.
+struct PixelConverter<'a> {
+    ihdr: &'a ImageHeader,
+    palette: &'a [u8],
+    transparency: &'a [u8],
+}
+
+impl PixelConverter<'_> {
+    fn pixel(&self, row: &[u8], x: usize) -> Result<[u8; 4], PngError> {
+        match self.ihdr.color_type {
+            0 => {
+                let value = sample(row, x, self.ihdr.bit_depth);
+                let max = (1u16 << self.ihdr.bit_depth) - 1;
+                // tRNS holds one 16-bit grey level to treat as transparent
+                let alpha = match *self.transparency {
+                    [hi, lo, ..] if u16::from_be_bytes([hi, lo]) == value as u16 => 0,
+                    _ => 255,
+                };
+                let grey = (value as u16 * 255 / max) as u8;
+                Ok([grey, grey, grey, alpha])
+            }
+            2 => {
+                let px = &row[x * 3..x * 3 + 3];
+                let alpha = match *self.transparency {
+                    [r0, r1, g0, g1, b0, b1, ..]
+                        if [r0, r1, g0, g1, b0, b1] == [0, px[0], 0, px[1], 0, px[2]] =>
+                    {
+                        0
+                    }
+                    _ => 255,
+                };
+                Ok([px[0], px[1], px[2], alpha])
+            }
+            3 => {
+                let index = sample(row, x, self.ihdr.bit_depth) as usize;
+                let rgb = self
+                    .palette
+                    .get(index * 3..index * 3 + 3)
+                    .ok_or(PngError::Malformed("palette index out of range"))?;
+                // tRNS holds alpha for the first few palette entries
+                let alpha = self.transparency.get(index).copied().unwrap_or(255);
+                Ok([rgb[0], rgb[1], rgb[2], alpha])
+            }
+            4 => {
+                let (grey, alpha) = (row[x * 2], row[x * 2 + 1]);
+                Ok([grey, grey, grey, alpha])
+            }
+            _ => {
+                let px = &row[x * 4..x * 4 + 4];
+                Ok([px[0], px[1], px[2], px[3]])
+            }
+        }
+    }
+}
+
+/// The `x`th sample of a row packed at `bit_depth` bits per sample, most
+/// significant bits first.
+fn sample(row: &[u8], x: usize, bit_depth: u8) -> u8 {
+    let depth = bit_depth as usize;
+    let bit = x * depth;
+    let shift = 8 - depth - bit % 8;
+    (row[bit / 8] >> shift) & ((1u16 << depth) - 1) as u8
+}
+
+/// Undoes a row's filter in place. `prev` is the previous unfiltered row of
+/// the same pass, all zeroes for the first.
+fn unfilter(filter: u8, row: &mut [u8], prev: &[u8], stride: usize) -> Result<(), PngError> {
+    match filter {
+        0 => {}
+        1 => {
+            for i in stride..row.len() {
+                row[i] = row[i].wrapping_add(row[i - stride]);
+            }
+        }
+        2 => {
+            for (b, &up) in row.iter_mut().zip(prev) {
+                *b = b.wrapping_add(up);
+            }
+        }
+        3 => {
+            for i in 0..row.len() {
+                let left = if i >= stride { row[i - stride] } else { 0 };
+                row[i] = row[i].wrapping_add(((left as u16 + prev[i] as u16) / 2) as u8);
+            }
+        }
+        4 => {
+            for i in 0..row.len() {
+                let (left, up_left) = if i >= stride {
+                    (row[i - stride], prev[i - stride])
+                } else {
+                    (0, 0)
+                };
+                row[i] = row[i].wrapping_add(paeth(left, prev[i], up_left));
+            }
+        }
+        _ => return Err(PngError::Malformed("unknown filter type")),
+    }
+    Ok(())
+}
+
+fn apply_filter(filter: u8, row: &[u8], prev: &[u8], stride: usize, out: &mut [u8]) {
+    for i in 0..row.len() {
+        let left = if i >= stride { row[i - stride] } else { 0 };
+        let up_left = if i >= stride { prev[i - stride] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => prev[i],
+            3 => ((left as u16 + prev[i] as u16) / 2) as u8,
+            _ => paeth(left, prev[i], up_left),
+        };
+        out[i] = row[i].wrapping_sub(predicted);
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_to_vec, dice_packed, encode_to_vec};
+
+    /// Assembles a PNG from an IHDR and already filtered scanlines.
+    fn png(ihdr: [u8; 13], extra: &[(&[u8; 4], &[u8])], scanlines: &[u8]) -> Vec<u8> {
+        let mut res = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut res, b"IHDR", &ihdr);
+        for (kind, body) in extra {
+            write_chunk(&mut res, kind, body);
+        }
+        write_chunk(&mut res, b"IDAT", &zlib::compress(scanlines));
+        write_chunk(&mut res, b"IEND", &[]);
+        res
+    }
+
+    fn ihdr(width: u32, height: u32, bit_depth: u8, color_type: u8, interlace: u8) -> [u8; 13] {
+        let mut res = [0u8; 13];
+        res[..4].copy_from_slice(&width.to_be_bytes());
+        res[4..8].copy_from_slice(&height.to_be_bytes());
+        res[8..].copy_from_slice(&[bit_depth, color_type, 0, 0, interlace]);
+        res
+    }
+
+    #[test]
+    fn dice_round_trip() {
+        let (header, pixels) = dice_packed();
+        let encoded = encode(&pixels, header).unwrap();
+        assert_eq!(decode(&encoded).unwrap(), (header, pixels.clone()));
+        // and on through QOI, as the command-line tool does it
+        let qoi = encode_to_vec(&pixels, header).unwrap();
+        assert_eq!(decode_to_vec(&qoi).unwrap().1, pixels);
+        assert!(encoded.len() < pixels.len() / 2);
+    }
+
+    #[test]
+    fn rgb_and_linear() {
+        let header = Header {
+            width: 3,
+            height: 2,
+            channels: Channels::RGB,
+            color_space: ColorSpace::Linear,
+        };
+        let pixels: Vec<u8> = (0..18).map(|i| i * 14).collect();
+        let encoded = encode(&pixels, header).unwrap();
+        assert_eq!(decode(&encoded).unwrap(), (header, pixels));
+    }
+
+    #[test]
+    fn palette_and_low_bit_depths() {
+        // 2-bit palette, 5 pixels wide so the last byte is partly padding
+        let palette = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120];
+        let scanlines = [0, 0b00_01_10_11, 0b01_000000];
+        let data = png(ihdr(5, 1, 2, 3, 0), &[(b"PLTE", &palette)], &scanlines);
+        let (header, pixels) = decode(&data).unwrap();
+        assert_eq!(header.channels, Channels::RGB);
+        assert_eq!(
+            pixels,
+            [10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120, 40, 50, 60]
+        );
+
+        // tRNS alpha for palette entries makes the image RGBA
+        let data = png(
+            ihdr(2, 1, 8, 3, 0),
+            &[(b"PLTE", &palette[..6]), (b"tRNS", &[128])],
+            &[0, 0, 1],
+        );
+        assert_eq!(decode(&data).unwrap().1, [10, 20, 30, 128, 40, 50, 60, 255]);
+
+        // 1-bit greyscale scales to 0 and 255
+        let data = png(ihdr(3, 1, 1, 0, 0), &[], &[0, 0b101_00000]);
+        assert_eq!(
+            decode(&data).unwrap().1,
+            [255, 255, 255, 0, 0, 0, 255, 255, 255]
+        );
+
+        // grey+alpha
+        let data = png(ihdr(1, 1, 8, 4, 0), &[], &[0, 7, 9]);
+        assert_eq!(decode(&data).unwrap().1, [7, 7, 7, 9]);
+    }
+
+    #[test]
+    fn filters() {
+        // a 2x6 RGB image; after an unfiltered first row, one row per filter
+        let rows: Vec<Vec<u8>> = (0..6u8)
+            .map(|y| {
+                (0..6u8)
+                    .map(|i| y.wrapping_mul(97) ^ i.wrapping_mul(45))
+                    .collect()
+            })
+            .collect();
+        let mut scanlines = Vec::new();
+        let mut prev = vec![0u8; 6];
+        for (y, row) in rows.iter().enumerate() {
+            let filter = y.saturating_sub(1) as u8;
+            let mut filtered = [0u8; 6];
+            apply_filter(filter, row, &prev, 3, &mut filtered);
+            scanlines.push(filter);
+            scanlines.extend_from_slice(&filtered);
+            prev.clone_from(row);
+        }
+        let (_, pixels) = decode(&png(ihdr(2, 6, 8, 2, 0), &[], &scanlines)).unwrap();
+        assert_eq!(pixels, rows.concat());
+    }
+
+    #[test]
+    fn adam7() {
+        // a 10x9 RGBA image stored interlaced, unfiltered
+        let (width, height) = (10usize, 9usize);
+        let pixel = |x: usize, y: usize| [x as u8, y as u8, (x * y) as u8, 255];
+        let mut scanlines = Vec::new();
+        for &(x0, y0, dx, dy) in &ADAM7 {
+            if x0 >= width || y0 >= height {
+                continue;
+            }
+            for y in (y0..height).step_by(dy) {
+                scanlines.push(0);
+                for x in (x0..width).step_by(dx) {
+                    scanlines.extend_from_slice(&pixel(x, y));
+                }
+            }
+        }
+        let data = png(ihdr(width as u32, height as u32, 8, 6, 1), &[], &scanlines);
+        let (header, pixels) = decode(&data).unwrap();
+        assert_eq!((header.width, header.height), (10, 9));
+        let expected: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| pixel(x, y)))
+            .collect();
+        assert_eq!(pixels, expected);
+    }
+
+    #[test]
+    fn errors() {
+        let (header, pixels) = dice_packed();
+        let good = encode(
+            &pixels[..4 * 64],
+            Header {
+                width: 8,
+                height: 8,
+                ..header
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(decode(&good[1..]), Err(PngError::BadSignature)));
+        assert!(matches!(
+            decode(&good[..good.len() - 3]),
+            Err(PngError::TruncatedChunk { .. })
+        ));
+        let mut corrupt = good.clone();
+        corrupt[20] ^= 1;
+        assert!(matches!(
+            decode(&corrupt),
+            Err(PngError::BadCrc { chunk, offset: 8 }) if &chunk == b"IHDR"
+        ));
+
+        let sixteen_bit = png(ihdr(1, 1, 16, 2, 0), &[], &[0; 7]);
+        assert!(matches!(
+            decode(&sixteen_bit),
+            Err(PngError::Unsupported {
+                color_type: 2,
+                bit_depth: 16
+            })
+        ));
+        let bad_filter = png(ihdr(1, 1, 8, 2, 0), &[], &[5, 0, 0, 0]);
+        assert!(matches!(decode(&bad_filter), Err(PngError::Malformed(_))));
+        let short = png(ihdr(2, 2, 8, 2, 0), &[], &[0; 7]);
+        assert!(matches!(decode(&short), Err(PngError::Malformed(_))));
+        // a 1x1 image whose data inflates to 4 MiB is cut off at two bytes
+        let bomb = png(ihdr(1, 1, 8, 0, 0), &[], &[0; 1 << 22]);
+        assert!(bomb.len() < 1 << 16);
+        assert!(matches!(decode(&bomb), Err(PngError::BadDeflate(_))));
+
+        let tiny = Limits {
+            max_pixels: 10,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode_with_limits(&good, tiny),
+            Err(PngError::Image(QoiError::LimitExceeded { .. }))
+        ));
+        assert!(matches!(
+            encode(
+                &[],
+                Header {
+                    width: 0,
+                    height: 0,
+                    ..header
+                }
+            ),
+            Err(PngError::EmptyImage)
+        ));
+        assert!(matches!(
+            encode(&pixels[..10], header),
+            Err(PngError::Image(QoiError::BadInputLength { .. }))
+        ));
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    }
+}
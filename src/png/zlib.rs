@@ -0,0 +1,544 @@
+//! Just enough of zlib (RFC 1950) and DEFLATE (RFC 1951) for PNG image data.
+//!
+//! Inflate handles all three block types. Deflate emits a single block with
+//! the fixed Huffman codes, after a hash chain LZ77 pass; that gets most of
+//! the way to zlib's ratio on filtered image rows without building trees.
+
+use super::PngError;
+
+// base lengths and extra bits of length codes 257..=285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// base distances and extra bits of distance codes 0..=29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// the order code length code lengths are stored in a dynamic block header
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+const MAX_BITS: usize = 15;
+
+fn bad(msg: &'static str) -> PngError {
+    PngError::BadDeflate(msg)
+}
+
+/// Decompresses a zlib stream, checking its Adler-32 trailer. A stream that
+/// inflates to more than `max_len` bytes is an error, raised before the
+/// excess is written.
+pub(super) fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, PngError> {
+    let [cmf, flg, ..] = *data else {
+        return Err(bad("stream too short"));
+    };
+    if cmf & 0x0f != 8 || cmf >> 4 > 7 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+        return Err(bad("invalid zlib header"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(bad("preset dictionaries are not supported"));
+    }
+
+    let mut bits = BitReader::new(&data[2..]);
+    // DEFLATE cannot do better than about 1032:1, so a small stream claiming
+    // a large image does not get its memory up front
+    let mut out = Vec::with_capacity(max_len.min(data.len().saturating_mul(1032)));
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => stored_block(&mut bits, &mut out, max_len)?,
+            1 => {
+                let (lit, dist) = fixed_codes();
+                compressed_block(&mut bits, &mut out, max_len, &lit, &dist)?
+            }
+            2 => {
+                let (lit, dist) = dynamic_codes(&mut bits)?;
+                compressed_block(&mut bits, &mut out, max_len, &lit, &dist)?
+            }
+            _ => return Err(bad("invalid block type")),
+        }
+        if last {
+            break;
+        }
+    }
+
+    bits.align();
+    let trailer = bits.rest();
+    if trailer.len() < 4 {
+        return Err(bad("missing checksum"));
+    }
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(&out) {
+        return Err(bad("checksum mismatch"));
+    }
+    Ok(out)
+}
+
+/// Checks that `len` more bytes of output stay within `max_len`.
+fn room_for(out: &[u8], len: usize, max_len: usize) -> Result<(), PngError> {
+    if len > max_len - out.len() {
+        return Err(bad("inflates to more than the image holds"));
+    }
+    Ok(())
+}
+
+fn stored_block(bits: &mut BitReader, out: &mut Vec<u8>, max_len: usize) -> Result<(), PngError> {
+    bits.align();
+    let len = bits.bits(16)?;
+    if bits.bits(16)? != !len & 0xffff {
+        return Err(bad("stored block length does not match its complement"));
+    }
+    let data = bits.take(len as usize)?;
+    room_for(out, data.len(), max_len)?;
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+fn compressed_block(
+    bits: &mut BitReader,
+    out: &mut Vec<u8>,
+    max_len: usize,
+    lit: &Huffman,
+    dist: &Huffman,
+) -> Result<(), PngError> {
+    loop {
+        let symbol = lit.decode(bits)? as usize;
+        match symbol {
+            0..=255 => {
+                room_for(out, 1, max_len)?;
+                out.push(symbol as u8)
+            }
+            256 => return Ok(()),
+            257..=285 => {
+                let i = symbol - 257;
+                let len = LENGTH_BASE[i] as usize + bits.bits(LENGTH_EXTRA[i])? as usize;
+                let i = dist.decode(bits)? as usize;
+                if i >= DIST_BASE.len() {
+                    return Err(bad("invalid distance code"));
+                }
+                let distance = DIST_BASE[i] as usize + bits.bits(DIST_EXTRA[i])? as usize;
+                if distance > out.len() {
+                    return Err(bad("distance reaches before the start of the stream"));
+                }
+                room_for(out, len, max_len)?;
+                // copies may overlap themselves, so go a byte at a time
+                let start = out.len() - distance;
+                for k in 0..len {
+                    out.push(out[start + k]);
+                }
+            }
+            _ => return Err(bad("invalid length code")),
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    // the fixed tables are complete, so building them cannot fail
+    let lit = Huffman::new(&lengths).unwrap_or_default();
+    let dist = Huffman::new(&[5; 30]).unwrap_or_default();
+    (lit, dist)
+}
+
+fn dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), PngError> {
+    let lit_count = bits.bits(5)? as usize + 257;
+    let dist_count = bits.bits(5)? as usize + 1;
+    let code_count = bits.bits(4)? as usize + 4;
+    if lit_count > 286 || dist_count > 30 {
+        return Err(bad("too many codes in dynamic block"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_count] {
+        code_lengths[i] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; lit_count + dist_count];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => {
+                let prev = *lengths[..i]
+                    .last()
+                    .ok_or(bad("repeat with no previous length"))?;
+                (prev, 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(bad("code lengths overrun the table"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(bad("no end of block code"));
+    }
+
+    let (lit, dist) = lengths.split_at(lit_count);
+    Ok((Huffman::new(lit)?, Huffman::new(dist)?))
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length
+/// and the symbols in code order.
+#[derive(Default)]
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, PngError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // incomplete codes are allowed (a lone distance code is common), but
+        // oversubscribed ones cannot be decoded
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(bad("oversubscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, PngError> {
+        // walk down the code one bit at a time; `first` is the first code of
+        // the current length and `index` the position of its symbol
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(bad("invalid Huffman code"))
+    }
+}
+
+/// Reads bits least significant first, as DEFLATE packs them.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u8) -> Result<u32, PngError> {
+        while self.count < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or(bad("unexpected end of data"))?;
+            self.buf |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.buf & ((1u64 << n) - 1) as u32;
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drops the bits left over in the current byte. Bytes are only pulled
+    /// in as they are needed, so at most seven are ever buffered.
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PngError> {
+        let data = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(bad("unexpected end of data"))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+}
+
+const WINDOW: usize = 1 << 15;
+const HASH_BITS: u32 = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// how many earlier positions with the same hash to try before settling
+const MAX_CHAIN: usize = 64;
+
+/// Compresses `data` into a zlib stream.
+pub(super) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    // 32K window, default compression level; 0x789c is a multiple of 31
+    out.bytes.extend_from_slice(&[0x78, 0x9c]);
+    out.put(1, 1); // last block
+    out.put(1, 2); // fixed Huffman codes
+
+    // positions are stored plus one, so zero means no earlier position
+    let mut head = vec![0u32; 1 << HASH_BITS];
+    let mut prev = vec![0u32; WINDOW];
+    let mut i = 0;
+    while i < data.len() {
+        let (len, distance) = longest_match(data, i, &head, &prev);
+        if len >= MIN_MATCH {
+            out.put_length(len);
+            out.put_distance(distance);
+            for k in i..i + len {
+                insert(data, k, &mut head, &mut prev);
+            }
+            i += len;
+        } else {
+            out.put_literal(data[i]);
+            insert(data, i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    out.put_symbol(256);
+
+    let mut bytes = out.finish();
+    bytes.extend_from_slice(&adler32(data).to_be_bytes());
+    bytes
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let key = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]);
+    (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Makes position `i` the newest in its hash chain.
+fn insert(data: &[u8], i: usize, head: &mut [u32], prev: &mut [u32]) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash(data, i);
+        prev[i % WINDOW] = head[h];
+        head[h] = i as u32 + 1;
+    }
+}
+
+/// The longest earlier match for the bytes at `i`, as (length, distance).
+fn longest_match(data: &[u8], i: usize, head: &[u32], prev: &[u32]) -> (usize, usize) {
+    if i + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let max_len = MAX_MATCH.min(data.len() - i);
+    let (mut best_len, mut best_distance) = (0, 0);
+    let mut candidate = head[hash(data, i)] as usize;
+    for _ in 0..MAX_CHAIN {
+        if candidate == 0 {
+            break;
+        }
+        let j = candidate - 1;
+        if i - j > WINDOW {
+            break;
+        }
+        let len = data[j..]
+            .iter()
+            .zip(&data[i..i + max_len])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > best_len {
+            (best_len, best_distance) = (len, i - j);
+            if len == max_len {
+                break;
+            }
+        }
+        let next = prev[j % WINDOW] as usize;
+        // the slot may have been reused by a newer position
+        if next >= candidate {
+            break;
+        }
+        candidate = next;
+    }
+    (best_len, best_distance)
+}
+
+/// Writes bits least significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buf: u64,
+    count: u8,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, n: u8) {
+        self.buf |= (value as u64) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.bytes.push(self.buf as u8);
+            self.buf >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit.
+    fn put_code(&mut self, code: u32, len: u8) {
+        self.put(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn put_symbol(&mut self, symbol: u16) {
+        let (code, len) = match symbol {
+            0..=143 => (0x30 + symbol as u32, 8),
+            144..=255 => (0x190 + symbol as u32 - 144, 9),
+            256..=279 => (symbol as u32 - 256, 7),
+            _ => (0xc0 + symbol as u32 - 280, 8),
+        };
+        self.put_code(code, len);
+    }
+
+    fn put_literal(&mut self, byte: u8) {
+        self.put_symbol(byte as u16);
+    }
+
+    fn put_length(&mut self, len: usize) {
+        let i = LENGTH_BASE.partition_point(|&base| base as usize <= len) - 1;
+        self.put_symbol(257 + i as u16);
+        self.put((len - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i]);
+    }
+
+    fn put_distance(&mut self, distance: usize) {
+        let i = DIST_BASE.partition_point(|&base| base as usize <= distance) - 1;
+        self.put_code(i as u32, 5);
+        self.put((distance - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buf as u8);
+        }
+        self.bytes
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that can be summed before `b` could overflow
+    for block in data.chunks(5552) {
+        for &byte in block {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut noise = 1u32;
+        let inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabcabc".to_vec(),
+            vec![7; 100_000],
+            (0..70_000)
+                .map(|_| {
+                    noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (noise >> 16) as u8 & 0x0f
+                })
+                .collect(),
+        ];
+        for input in inputs {
+            let compressed = compress(&input);
+            assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        }
+        assert!(compress(&[7; 100_000]).len() < 1000);
+    }
+
+    #[test]
+    fn output_is_capped() {
+        let bomb = compress(&[0; 1 << 20]);
+        assert_eq!(decompress(&bomb, 1 << 20).unwrap().len(), 1 << 20);
+        assert!(matches!(
+            decompress(&bomb, (1 << 20) - 1),
+            Err(PngError::BadDeflate(_))
+        ));
+        let stored = [
+            0x78, 0x01, 0x01, 0x05, 0x00, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o', 0x06, 0x2c,
+            0x02, 0x15,
+        ];
+        assert!(decompress(&stored, 4).is_err());
+    }
+
+    #[test]
+    fn stored_and_dynamic_blocks() {
+        // "hello" as a stored block, then zlib -9 output that uses a dynamic block
+        let stored = [
+            0x78, 0x01, 0x01, 0x05, 0x00, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o', 0x06, 0x2c,
+            0x02, 0x15,
+        ];
+        assert_eq!(decompress(&stored, 5).unwrap(), b"hello");
+
+        let text = b"ccbdddcdacbdaabdbdbcccdddcdbcaabccddcbbaaabddcbd";
+        let dynamic = [
+            0x78, 0xda, 0x1d, 0x89, 0xc1, 0x0d, 0x00, 0x30, 0x10, 0x82, 0x66, 0x55, 0xd8, 0x7f,
+            0x86, 0x7a, 0x8d, 0x0f, 0x08, 0x42, 0x55, 0xcc, 0x98, 0xd4, 0x0d, 0xf8, 0xa9, 0x2c,
+            0x9c, 0xd3, 0xe6, 0xbe, 0x89, 0x0f, 0xc6, 0x50, 0x12, 0x86,
+        ];
+        assert_eq!(decompress(&dynamic, text.len()).unwrap(), text);
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        let good = compress(b"some bytes to compress, some bytes to compress");
+        for len in 0..good.len() {
+            assert!(decompress(&good[..len], usize::MAX).is_err());
+        }
+        let mut bad_sum = good.clone();
+        *bad_sum.last_mut().unwrap() ^= 1;
+        assert!(decompress(&bad_sum, usize::MAX).is_err());
+        assert!(decompress(&[0x78, 0x9c, 0xff], usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,493 @@
+//! Netpbm import and export: PPM (P6, or P3 in ASCII), PGM (P5, or P2) and
+//! PAM (P7).
+//!
+//! Any maxval up to 65535 is read, and samples are scaled to 8 bits. PAM files
+//! may hold GRAYSCALE, GRAYSCALE_ALPHA, RGB or RGB_ALPHA tuples; images with
+//! alpha come out as RGBA and everything else as RGB, in the same layout as
+//! [`decode_to_vec`](crate::decode_to_vec). Files are always written with a
+//! maxval of 255.
+
+use std::fmt;
+
+use crate::{
+    check_input_len, ChannelOrder, Channels, ColorSpace, Header, Layout, Limits, QOIImage, QoiError,
+};
+
+// longest line an ASCII file should have, as the Netpbm spec asks
+const MAX_LINE_LEN: usize = 70;
+
+/// Which Netpbm format to write.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// P6 RGB; alpha is dropped.
+    Ppm,
+    /// P3, the ASCII form of [`Format::Ppm`].
+    PpmAscii,
+    /// P5 greyscale, from the Rec. 601 luma of each pixel; alpha is dropped.
+    Pgm,
+    /// P2, the ASCII form of [`Format::Pgm`].
+    PgmAscii,
+    /// P7 with an RGB or RGB_ALPHA tuple type, matching the image's channels.
+    /// PAM has no ASCII form.
+    Pam,
+}
+
+/// Everything that can go wrong while reading a Netpbm file.
+#[derive(Debug)]
+pub enum PnmError {
+    /// The file does not start with a magic number this module reads.
+    BadMagic { found: [u8; 2] },
+    /// A header field is missing or not a valid value.
+    BadHeader(&'static str),
+    /// The PAM tuple type or depth is not one this module reads.
+    Unsupported(&'static str),
+    /// The pixel data ended before `width * height` pixels were read.
+    TruncatedData,
+    /// A sample is not a number, or is above the maxval.
+    BadSample { index: usize },
+    /// The image is too large for the limits, or a buffer handed to the
+    /// encoder has the wrong length.
+    Image(QoiError),
+}
+
+impl fmt::Display for PnmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PnmError::BadMagic { found } => write!(
+                f,
+                "Malformed Netpbm file: unknown magic number {:?}",
+                String::from_utf8_lossy(found)
+            ),
+            PnmError::BadHeader(msg) => write!(f, "Malformed Netpbm file: {msg}"),
+            PnmError::Unsupported(msg) => write!(f, "Unsupported Netpbm file: {msg}"),
+            PnmError::TruncatedData => {
+                write!(f, "Malformed Netpbm file: pixel data ends early")
+            }
+            PnmError::BadSample { index } => {
+                write!(f, "Malformed Netpbm file: invalid sample number {index}")
+            }
+            PnmError::Image(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PnmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PnmError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<QoiError> for PnmError {
+    fn from(e: QoiError) -> Self {
+        PnmError::Image(e)
+    }
+}
+
+/// Decodes a PPM, PGM or PAM file into packed RGB or RGBA pixels, with the
+/// default [`Limits`].
+pub fn decode(data: &[u8]) -> Result<(Header, Vec<u8>), PnmError> {
+    decode_with_limits(data, Limits::default())
+}
+
+/// Like [`decode`], but rejects images the `limits` do not allow before
+/// reading any pixels.
+pub fn decode_with_limits(data: &[u8], limits: Limits) -> Result<(Header, Vec<u8>), PnmError> {
+    let mut parser = Parser { data, pos: 2 };
+    let (width, height, depth, maxval, ascii) = match data.get(..2) {
+        Some(b"P2") => parser.pnm_header(1, true)?,
+        Some(b"P3") => parser.pnm_header(3, true)?,
+        Some(b"P5") => parser.pnm_header(1, false)?,
+        Some(b"P6") => parser.pnm_header(3, false)?,
+        Some(b"P7") => parser.pam_header()?,
+        Some(&[a, b]) => return Err(PnmError::BadMagic { found: [a, b] }),
+        _ => return Err(PnmError::BadHeader("file is too short")),
+    };
+    if maxval == 0 || maxval > u16::MAX as u32 {
+        return Err(PnmError::BadHeader("maxval out of range"));
+    }
+
+    let header = Header {
+        width,
+        height,
+        channels: if depth % 2 == 0 {
+            Channels::RGBA
+        } else {
+            Channels::RGB
+        },
+        color_space: ColorSpace::SRGB,
+    };
+    limits.check(&header)?;
+
+    let count = (header.pixel_count() as usize).saturating_mul(depth);
+    let samples = if ascii {
+        parser.ascii_samples(count, maxval)?
+    } else {
+        parser.binary_samples(count, maxval)?
+    };
+
+    let pixels = match depth {
+        1 => samples.iter().flat_map(|&v| [v, v, v]).collect(),
+        2 => samples
+            .chunks_exact(2)
+            .flat_map(|px| [px[0], px[0], px[0], px[1]])
+            .collect(),
+        _ => samples,
+    };
+    Ok((header, pixels))
+}
+
+/// Decodes a PPM, PGM or PAM file and encodes it as a [`QOIImage`].
+pub fn decode_image(data: &[u8]) -> Result<QOIImage, PnmError> {
+    let (header, pixels) = decode(data)?;
+    Ok(QOIImage::from_packed(&pixels, header)?)
+}
+
+/// Encodes packed pixels, three or four bytes each depending on
+/// `header.channels`, in the given Netpbm format.
+pub fn encode(pixels: &[u8], header: Header, format: Format) -> Result<Vec<u8>, PnmError> {
+    check_input_len(pixels, &header)?;
+    let (width, height) = (header.width, header.height);
+    let rgb = || pixels.chunks_exact(header.channels.count());
+
+    let (mut res, samples, ascii): (Vec<u8>, Vec<u8>, bool) = match format {
+        Format::Ppm | Format::PpmAscii => {
+            let ascii = format == Format::PpmAscii;
+            let magic = if ascii { "P3" } else { "P6" };
+            let samples = rgb().flat_map(|px| [px[0], px[1], px[2]]).collect();
+            let header = format!("{magic}\n{width} {height}\n255\n");
+            (header.into_bytes(), samples, ascii)
+        }
+        Format::Pgm | Format::PgmAscii => {
+            let ascii = format == Format::PgmAscii;
+            let magic = if ascii { "P2" } else { "P5" };
+            let samples = rgb().map(|px| luma(px[0], px[1], px[2])).collect();
+            let header = format!("{magic}\n{width} {height}\n255\n");
+            (header.into_bytes(), samples, ascii)
+        }
+        Format::Pam => {
+            let (depth, tuple_type) = match header.channels {
+                Channels::RGB => (3, "RGB"),
+                Channels::RGBA => (4, "RGB_ALPHA"),
+            };
+            let header = format!(
+                "P7\nWIDTH {width}\nHEIGHT {height}\nDEPTH {depth}\nMAXVAL 255\n\
+                 TUPLTYPE {tuple_type}\nENDHDR\n"
+            );
+            (header.into_bytes(), pixels.to_vec(), false)
+        }
+    };
+
+    if ascii {
+        let mut line_len = 0;
+        for sample in samples {
+            let text = sample.to_string();
+            if line_len > 0 && line_len + 1 + text.len() > MAX_LINE_LEN {
+                res.push(b'\n');
+                line_len = 0;
+            } else if line_len > 0 {
+                res.push(b' ');
+                line_len += 1;
+            }
+            res.extend_from_slice(text.as_bytes());
+            line_len += text.len();
+        }
+        res.push(b'\n');
+    } else {
+        res.extend_from_slice(&samples);
+    }
+    Ok(res)
+}
+
+/// Decodes a [`QOIImage`] and encodes it in the given Netpbm format.
+pub fn encode_image(image: &QOIImage, format: Format) -> Result<Vec<u8>, PnmError> {
+    let header = image.header();
+    let order = match header.channels {
+        Channels::RGB => ChannelOrder::RGB,
+        Channels::RGBA => ChannelOrder::RGBA,
+    };
+    let layout = Layout::packed(order, header.width);
+    let mut pixels = vec![0u8; layout.required_len(header.width, header.height) as usize];
+    image.decode_into(&mut pixels, layout)?;
+    encode(&pixels, header, format)
+}
+
+/// Rec. 601 luma, the usual weights for turning RGB into grey.
+fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114 + 500) / 1000) as u8
+}
+
+/// Scales a sample from `0..=maxval` to `0..=255`, rounding to nearest.
+fn scale(value: u32, maxval: u32) -> u8 {
+    if maxval == 255 {
+        value as u8
+    } else {
+        ((value * 255 + maxval / 2) / maxval) as u8
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Skips whitespace and `#` comments, which run to the end of the line.
+    fn skip_space(&mut self) {
+        while let Some(&b) = self.data.get(self.pos) {
+            if b == b'#' {
+                while self.data.get(self.pos).is_some_and(|&b| b != b'\n') {
+                    self.pos += 1;
+                }
+            } else if b.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn token(&mut self) -> Option<&'a [u8]> {
+        self.skip_space();
+        let start = self.pos;
+        while self
+            .data
+            .get(self.pos)
+            .is_some_and(|b| !b.is_ascii_whitespace() && *b != b'#')
+        {
+            self.pos += 1;
+        }
+        (self.pos > start).then(|| &self.data[start..self.pos])
+    }
+
+    fn number(&mut self, what: &'static str) -> Result<u32, PnmError> {
+        self.token()
+            .and_then(parse_number)
+            .ok_or(PnmError::BadHeader(what))
+    }
+
+    /// Reads the width, height and maxval of a P2, P3, P5 or P6 header,
+    /// leaving the parser at the first sample.
+    fn pnm_header(
+        &mut self,
+        depth: usize,
+        ascii: bool,
+    ) -> Result<(u32, u32, usize, u32, bool), PnmError> {
+        let width = self.number("invalid width")?;
+        let height = self.number("invalid height")?;
+        let maxval = self.number("invalid maxval")?;
+        // exactly one whitespace byte separates the header from binary data
+        if !self.data.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            return Err(PnmError::BadHeader("no whitespace after maxval"));
+        }
+        self.pos += 1;
+        Ok((width, height, depth, maxval, ascii))
+    }
+
+    /// Reads a P7 header up to and including its ENDHDR line.
+    fn pam_header(&mut self) -> Result<(u32, u32, usize, u32, bool), PnmError> {
+        let (mut width, mut height, mut depth, mut maxval) = (None, None, None, None);
+        let mut tuple_type: Option<&[u8]> = None;
+        loop {
+            let field = self.token().ok_or(PnmError::BadHeader("no ENDHDR line"))?;
+            match field {
+                b"WIDTH" => width = Some(self.number("invalid WIDTH")?),
+                b"HEIGHT" => height = Some(self.number("invalid HEIGHT")?),
+                b"DEPTH" => depth = Some(self.number("invalid DEPTH")?),
+                b"MAXVAL" => maxval = Some(self.number("invalid MAXVAL")?),
+                b"TUPLTYPE" => tuple_type = self.token(),
+                b"ENDHDR" => break,
+                _ => return Err(PnmError::BadHeader("unknown header field")),
+            }
+        }
+        // ENDHDR ends with a newline, and the data starts right after it
+        while self.data.get(self.pos).is_some_and(|&b| b != b'\n') {
+            self.pos += 1;
+        }
+        self.pos += 1;
+
+        let depth = depth.ok_or(PnmError::BadHeader("missing DEPTH"))? as usize;
+        let expected_depth = match tuple_type {
+            Some(b"GRAYSCALE") | Some(b"BLACKANDWHITE") => 1,
+            Some(b"GRAYSCALE_ALPHA") | Some(b"BLACKANDWHITE_ALPHA") => 2,
+            Some(b"RGB") => 3,
+            Some(b"RGB_ALPHA") => 4,
+            None if (1..=4).contains(&depth) => depth,
+            _ => return Err(PnmError::Unsupported("unknown TUPLTYPE")),
+        };
+        if depth != expected_depth {
+            return Err(PnmError::Unsupported("DEPTH does not match TUPLTYPE"));
+        }
+        Ok((
+            width.ok_or(PnmError::BadHeader("missing WIDTH"))?,
+            height.ok_or(PnmError::BadHeader("missing HEIGHT"))?,
+            depth,
+            maxval.ok_or(PnmError::BadHeader("missing MAXVAL"))?,
+            false,
+        ))
+    }
+
+    /// Reads `count` samples of one byte each, or two big-endian bytes each
+    /// when the maxval needs them.
+    fn binary_samples(&mut self, count: usize, maxval: u32) -> Result<Vec<u8>, PnmError> {
+        let width = if maxval > 255 { 2 } else { 1 };
+        let data = self
+            .data
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..count.checked_mul(width)?))
+            .ok_or(PnmError::TruncatedData)?;
+        self.pos += data.len();
+        data.chunks_exact(width)
+            .enumerate()
+            .map(|(index, bytes)| {
+                let value = match *bytes {
+                    [hi, lo] => u16::from_be_bytes([hi, lo]) as u32,
+                    _ => bytes[0] as u32,
+                };
+                if value > maxval {
+                    return Err(PnmError::BadSample { index });
+                }
+                Ok(scale(value, maxval))
+            })
+            .collect()
+    }
+
+    /// Reads `count` whitespace separated decimal samples.
+    fn ascii_samples(&mut self, count: usize, maxval: u32) -> Result<Vec<u8>, PnmError> {
+        // every sample takes at least two bytes, a digit and a separator
+        let mut samples = Vec::with_capacity(count.min(self.data.len() / 2 + 1));
+        for index in 0..count {
+            let token = self.token().ok_or(PnmError::TruncatedData)?;
+            match parse_number(token) {
+                Some(value) if value <= maxval => samples.push(scale(value, maxval)),
+                _ => return Err(PnmError::BadSample { index }),
+            }
+        }
+        Ok(samples)
+    }
+}
+
+fn parse_number(token: &[u8]) -> Option<u32> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice_packed;
+
+    fn rgb_header(width: u32, height: u32) -> Header {
+        Header {
+            width,
+            height,
+            channels: Channels::RGB,
+            color_space: ColorSpace::SRGB,
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let (header, pixels) = dice_packed();
+        let rgb: Vec<u8> = pixels
+            .chunks_exact(4)
+            .flat_map(|px| [px[0], px[1], px[2]])
+            .collect();
+        let rgb_header = Header {
+            channels: Channels::RGB,
+            ..header
+        };
+        for format in [Format::Ppm, Format::PpmAscii, Format::Pam] {
+            let encoded = encode(&pixels, header, format).unwrap();
+            let expected = match format {
+                Format::Pam => (header, pixels.clone()),
+                _ => (rgb_header, rgb.clone()),
+            };
+            assert_eq!(decode(&encoded).unwrap(), expected);
+        }
+
+        let ascii = encode(&pixels, header, Format::PgmAscii).unwrap();
+        assert!(ascii.split(|&b| b == b'\n').all(|line| line.len() <= 70));
+        assert_eq!(
+            decode(&ascii).unwrap(),
+            decode(&encode(&pixels, header, Format::Pgm).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn qoi_images() {
+        let data = std::fs::read("files/dice.qoi").unwrap();
+        let image = QOIImage::from_reader(data.as_slice()).unwrap();
+        let pam = encode_image(&image, Format::Pam).unwrap();
+        assert_eq!(decode_image(&pam).unwrap().serialize(), data);
+    }
+
+    #[test]
+    fn headers_and_scaling() {
+        // comments anywhere in the header, and a maxval of 15
+        let data = b"P2 # grey\n3 # width\n1\n15\n0 15 7\n";
+        let (header, pixels) = decode(data).unwrap();
+        assert_eq!(header, rgb_header(3, 1));
+        assert_eq!(pixels, [0, 0, 0, 255, 255, 255, 119, 119, 119]);
+
+        // 16-bit binary samples
+        let mut data = b"P5 2 1 65535\n".to_vec();
+        data.extend_from_slice(&[0xff, 0xff, 0x80, 0x00]);
+        assert_eq!(decode(&data).unwrap().1, [255, 255, 255, 128, 128, 128]);
+
+        // grey and alpha PAM
+        let data = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 2\nMAXVAL 255\nTUPLTYPE GRAYSCALE_ALPHA\nENDHDR\n\x09\x80";
+        let (header, pixels) = decode(data).unwrap();
+        assert_eq!(header.channels, Channels::RGBA);
+        assert_eq!(pixels, [9, 9, 9, 128]);
+
+        let pgm = encode(&[255, 0, 0], rgb_header(1, 1), Format::Pgm).unwrap();
+        assert_eq!(pgm, b"P5\n1 1\n255\n\x4c");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            decode(b"P4 1 1\n\0"),
+            Err(PnmError::BadMagic { found }) if &found == b"P4"
+        ));
+        assert!(matches!(decode(b"P"), Err(PnmError::BadHeader(_))));
+        assert!(matches!(
+            decode(b"P6 1 x 255\n"),
+            Err(PnmError::BadHeader(_))
+        ));
+        assert!(matches!(
+            decode(b"P6 1 1 0\n\0\0\0"),
+            Err(PnmError::BadHeader(_))
+        ));
+        assert!(matches!(
+            decode(b"P6 2 1 255\n\0\0\0"),
+            Err(PnmError::TruncatedData)
+        ));
+        assert!(matches!(
+            decode(b"P3 1 1 255\n1 2"),
+            Err(PnmError::TruncatedData)
+        ));
+        assert!(matches!(
+            decode(b"P3 1 1 7\n1 2 8"),
+            Err(PnmError::BadSample { index: 2 })
+        ));
+        assert!(matches!(
+            decode(b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n"),
+            Err(PnmError::Unsupported(_))
+        ));
+        let huge = Limits {
+            max_width: 10,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode_with_limits(b"P6 11 1 255\n", huge),
+            Err(PnmError::Image(QoiError::LimitExceeded { .. }))
+        ));
+        assert!(matches!(
+            encode(&[0; 5], rgb_header(2, 1), Format::Ppm),
+            Err(PnmError::Image(QoiError::BadInputLength { .. }))
+        ));
+    }
+}
@@ -0,0 +1,612 @@
+use std::io::{ErrorKind, Read, Write};
+
+use crate::{
+    packed_pixels, read_header, BlockBytes, Channels, Chunk, DecodeState, EncodeMode,
+    EncodeOptions, EncodeState, Header, Limits, QoiError, QOI_END_MARKER,
+};
+
+/// Decodes a QOI stream one row at a time.
+///
+/// Only the header, the 64 entry index, the previous pixel and an 8 KiB block
+/// of input are kept between rows, so memory use does not grow with the
+/// image. The chunks are read in blocks, as [`QOIImage::from_reader`] reads
+/// them, so there is no need to wrap the source in a `BufReader`. The end of
+/// the stream is read as a lenient [`DecodeOptions`](crate::DecodeOptions)
+/// reads it: chunks past the last pixel are dropped up to the first end marker
+/// after it.
+///
+/// [`QOIImage::from_reader`]: crate::QOIImage::from_reader
+pub struct StreamDecoder<R: Read> {
+    source: BlockBytes<R>,
+    header: Header,
+    state: DecodeState,
+    // copies of the previous pixel still owed by the last run chunk
+    pending_run: usize,
+    rows_read: u32,
+    // byte offset of the next byte to be read from the source
+    offset: usize,
+    failed: bool,
+}
+
+impl<R: Read> StreamDecoder<R> {
+    /// Reads the header, and nothing past it unless the image has no rows;
+    /// then the end marker is read as well.
+    pub fn new(source: R) -> Result<StreamDecoder<R>, QoiError> {
+        StreamDecoder::with_limits(source, Limits::default())
+    }
+
+    /// Reads the header and checks it against `limits`.
+    pub fn with_limits(mut source: R, limits: Limits) -> Result<StreamDecoder<R>, QoiError> {
+        let header = read_header(&mut source)?;
+        limits.check(&header)?;
+        let mut decoder = StreamDecoder {
+            source: BlockBytes::new(source),
+            header,
+            state: DecodeState::new(),
+            pending_run: 0,
+            rows_read: 0,
+            offset: 14,
+            failed: false,
+        };
+        // no row will be read, so nothing else would read the end marker
+        if decoder.rows_remaining() == 0 {
+            decoder.read_end_marker()?;
+        }
+        Ok(decoder)
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// The number of bytes in one row: `width * channels`.
+    pub fn row_len(&self) -> usize {
+        self.header.width as usize * self.header.channels.count()
+    }
+
+    /// The number of rows not yet read. An image of zero width has no rows,
+    /// as with [`QOIImage::to_rgba_mat`](crate::QOIImage::to_rgba_mat).
+    pub fn rows_remaining(&self) -> u32 {
+        if self.header.width == 0 {
+            return 0;
+        }
+        self.header.height - self.rows_read
+    }
+
+    /// Decodes the next row into `row`, packed with the header's channel count.
+    /// Reading the last row also reads on to the end marker.
+    ///
+    /// # Panics
+    ///
+    /// If `row` is not exactly [`StreamDecoder::row_len`] bytes, or every row
+    /// has already been read.
+    pub fn read_row(&mut self, row: &mut [u8]) -> Result<(), QoiError> {
+        assert_eq!(row.len(), self.row_len(), "row buffer has the wrong length");
+        assert!(self.rows_remaining() > 0, "every row has already been read");
+
+        let res = self.fill_row(row);
+        self.failed = res.is_err();
+        res
+    }
+
+    fn fill_row(&mut self, row: &mut [u8]) -> Result<(), QoiError> {
+        let channels = self.header.channels.count();
+        for px_out in row.chunks_exact_mut(channels) {
+            let px = if self.pending_run > 0 {
+                self.pending_run -= 1;
+                self.state.prev_px
+            } else {
+                let chunk = self.next_chunk()?;
+                let (px, n) = self.state.apply(&chunk);
+                self.pending_run = n - 1;
+                px
+            };
+            px_out.copy_from_slice(&<[u8; 4]>::from(px)[..channels]);
+        }
+        self.rows_read += 1;
+
+        if self.rows_read == self.header.height {
+            self.read_end_marker()?;
+        }
+        Ok(())
+    }
+
+    /// Reads up to and including the end marker, skipping any chunks before it.
+    fn read_end_marker(&mut self) -> Result<(), QoiError> {
+        // the end marker is seven 0x00 bytes followed by a single 0x01
+        let mut zeroes_so_far = 0;
+        loop {
+            let chunk_offset = self.offset;
+            let tag = match self.source.next() {
+                Some(Ok(tag)) => tag,
+                Some(Err(e)) => return Err(QoiError::Io(e)),
+                None => {
+                    return Err(QoiError::MissingEndMarker {
+                        offset: chunk_offset,
+                    })
+                }
+            };
+            self.offset += 1;
+            match tag {
+                0 => zeroes_so_far += 1,
+                1 if zeroes_so_far >= 7 => return Ok(()),
+                n => {
+                    zeroes_so_far = 0;
+                    for _ in 1..chunk_len(n) {
+                        self.read_byte(chunk_offset)?;
+                        self.offset += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<Chunk, QoiError> {
+        let chunk_offset = self.offset;
+        let mut buf = [0u8; 5];
+        buf[0] = self.read_byte(chunk_offset)?;
+        let len = chunk_len(buf[0]);
+        for byte in &mut buf[1..len] {
+            *byte = self.read_byte(chunk_offset)?;
+        }
+        self.offset += len;
+        let (chunk, _) = Chunk::read(&buf[..len], 0)?;
+        Ok(chunk)
+    }
+
+    /// Reads the next byte of the chunk at `chunk_offset`.
+    fn read_byte(&mut self, chunk_offset: usize) -> Result<u8, QoiError> {
+        match self.source.next() {
+            Some(Ok(byte)) => Ok(byte),
+            Some(Err(e)) => Err(QoiError::Io(e)),
+            None => Err(QoiError::TruncatedChunk {
+                offset: chunk_offset,
+            }),
+        }
+    }
+
+    /// Gives back the source. It is read a block at a time, so it may have
+    /// been read past the end marker.
+    pub fn into_inner(self) -> R {
+        self.source.into_inner()
+    }
+}
+
+/// The length of the chunk with the tag byte `tag`, tag included.
+fn chunk_len(tag: u8) -> usize {
+    match tag {
+        0b11111111 => 5,
+        0b11111110 => 4,
+        n if n >> 6 == 0b10 => 2,
+        _ => 1,
+    }
+}
+
+/// Yields each remaining row as a freshly allocated buffer. Iteration stops
+/// after the first error.
+impl<R: Read> Iterator for StreamDecoder<R> {
+    type Item = Result<Vec<u8>, QoiError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.rows_remaining() == 0 {
+            return None;
+        }
+        let mut row = vec![0u8; self.row_len()];
+        Some(self.read_row(&mut row).map(|()| row))
+    }
+}
+
+/// Encodes a QOI stream from pixels pushed a row, or any number of pixels,
+/// at a time.
+///
+/// The header is written as soon as the encoder is created, and chunks are
+/// written as soon as they are complete. Chunks the sink does not take, say
+/// because it returned [`ErrorKind::WouldBlock`], are kept and sent before
+/// anything else; an error from a write means none of its pixels were taken. Pixels are packed with the header's
+/// channel count; through [`std::io::Write`] they may also be split anywhere,
+/// even mid-pixel.
+pub struct StreamEncoder<W: Write> {
+    sink: W,
+    header: Header,
+    state: EncodeState,
+    pixels_written: u64,
+    // the start of a pixel split across two writes
+    partial: [u8; 4],
+    partial_len: usize,
+    // chunks waiting to be written to the sink
+    buf: Vec<u8>,
+}
+
+impl<W: Write> StreamEncoder<W> {
+    /// Writes the header to `sink`.
+    pub fn new(sink: W, header: Header) -> Result<StreamEncoder<W>, QoiError> {
+        StreamEncoder::with_limits(sink, header, Limits::none())
+    }
+
+    /// Checks `header` against `limits` before writing it to `sink`.
+    pub fn with_limits(
+        sink: W,
+        header: Header,
+        limits: Limits,
+    ) -> Result<StreamEncoder<W>, QoiError> {
+        let options = EncodeOptions {
+            channels: Some(header.channels),
+            color_space: header.color_space,
+            limits,
+            mode: EncodeMode::default(),
+        };
+        StreamEncoder::with_options(sink, header.width, header.height, options)
+    }
+
+    /// Builds the header from `options`, checks it against their limits and
+    /// writes it to `sink`. The channel count cannot be inferred from pixels
+    /// that have not arrived yet, so it is RGBA unless `options` set it.
+    pub fn with_options(
+        mut sink: W,
+        width: u32,
+        height: u32,
+        options: EncodeOptions,
+    ) -> Result<StreamEncoder<W>, QoiError> {
+        let header = Header {
+            width,
+            height,
+            channels: options.channels.unwrap_or(Channels::RGBA),
+            color_space: options.color_space,
+        };
+        options.limits.check(&header)?;
+        let mut buf = Vec::with_capacity(14);
+        header.write_to(&mut buf);
+        sink.write_all(&buf)?;
+        buf.clear();
+
+        Ok(StreamEncoder {
+            sink,
+            header,
+            state: EncodeState::new(options.mode),
+            pixels_written: 0,
+            partial: [0; 4],
+            partial_len: 0,
+            buf,
+        })
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// The number of whole pixels taken so far.
+    pub fn pixels_written(&self) -> u64 {
+        self.pixels_written
+    }
+
+    /// Encodes whole pixels, packed with the header's channel count. A row is
+    /// just `width` of them.
+    pub fn write_pixels(&mut self, pixels: &[u8]) -> Result<(), QoiError> {
+        let channels = self.header.channels.count();
+        let len = self.partial_len + pixels.len();
+        if self.partial_len > 0 || !len.is_multiple_of(channels) {
+            return Err(QoiError::UnalignedInput {
+                len: len as u64,
+                channels,
+            });
+        }
+        self.check_room(pixels.len() / channels)?;
+        self.flush_buf()?;
+        self.encode(pixels);
+        // the pixels are taken now, so a failure to send their chunks is
+        // left for the next call to report
+        let _ = self.flush_buf();
+        Ok(())
+    }
+
+    /// Flushes the last run and writes the end marker, giving back the sink.
+    /// Fails if fewer than `width * height` pixels were written.
+    pub fn finish(mut self) -> Result<W, QoiError> {
+        let channels = self.header.channels.count() as u64;
+        let expected = self.header.pixel_count();
+        if self.pixels_written != expected || self.partial_len > 0 {
+            return Err(QoiError::BadInputLength {
+                expected: expected.saturating_mul(channels),
+                actual: self.pixels_written * channels + self.partial_len as u64,
+            });
+        }
+
+        let buf = &mut self.buf;
+        self.state.flush(&mut |chunk: Chunk| chunk.write_to(buf));
+        self.buf.extend_from_slice(&QOI_END_MARKER);
+        self.flush_buf()?;
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+
+    fn check_room(&self, pixels: usize) -> Result<(), QoiError> {
+        let channels = self.header.channels.count() as u64;
+        let expected = self.header.pixel_count();
+        if self.pixels_written + pixels as u64 > expected {
+            return Err(QoiError::BadInputLength {
+                expected: expected.saturating_mul(channels),
+                actual: (self.pixels_written + pixels as u64) * channels,
+            });
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, pixels: &[u8]) {
+        let channels = self.header.channels;
+        let buf = &mut self.buf;
+        let mut emit = |chunk: Chunk| chunk.write_to(buf);
+        for px in packed_pixels(pixels, channels) {
+            self.state.push(px, &mut emit);
+        }
+        self.pixels_written += (pixels.len() / channels.count()) as u64;
+    }
+
+    /// Sends the chunks waiting in `buf`, keeping whatever the sink does not
+    /// take.
+    fn flush_buf(&mut self) -> std::io::Result<()> {
+        while !self.buf.is_empty() {
+            match self.sink.write(&self.buf) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Takes raw pixel bytes, which need not line up with pixel boundaries.
+/// Writing more than `width * height` pixels fails with
+/// [`ErrorKind::InvalidInput`].
+impl<W: Write> Write for StreamEncoder<W> {
+    fn write(&mut self, mut data: &[u8]) -> std::io::Result<usize> {
+        let channels = self.header.channels.count();
+        let total = data.len();
+        // a trailing partial pixel still needs room
+        let started = (self.partial_len + data.len()).div_ceil(channels);
+        self.check_room(started)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        self.flush_buf()?;
+
+        if self.partial_len > 0 {
+            let take = (channels - self.partial_len).min(data.len());
+            self.partial[self.partial_len..self.partial_len + take].copy_from_slice(&data[..take]);
+            self.partial_len += take;
+            data = &data[take..];
+            if self.partial_len < channels {
+                return Ok(total);
+            }
+            let px = self.partial;
+            self.encode(&px[..channels]);
+            self.partial_len = 0;
+        }
+
+        let split = data.len() - data.len() % channels;
+        self.encode(&data[..split]);
+        self.partial[..data.len() - split].copy_from_slice(&data[split..]);
+        self.partial_len = data.len() - split;
+
+        // as in write_pixels, the data is taken whether or not the sink is
+        // ready for its chunks
+        let _ = self.flush_buf();
+        Ok(total)
+    }
+
+    /// Sends any waiting chunks and flushes the sink. A pending run stays
+    /// pending until [`StreamEncoder::finish`].
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_buf()?;
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::{encode_to_vec, encode_to_vec_with_mode, ColorSpace};
+
+    #[test]
+    fn dice_rows_match_decode_to_vec() {
+        let decoder = StreamDecoder::new(File::open("files/dice.qoi").unwrap()).unwrap();
+        assert_eq!(decoder.header().channels, Channels::RGBA);
+        assert_eq!(decoder.row_len(), 800 * 4);
+
+        let rows = decoder.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows.len(), 600);
+        assert!(rows.concat() == std::fs::read("files/dice2.rgba").unwrap());
+    }
+
+    #[test]
+    fn truncated_stream_fails_on_the_broken_row() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let mut decoder = StreamDecoder::new(&dice[..dice.len() / 2]).unwrap();
+        let mut row = vec![0u8; decoder.row_len()];
+        let mut good_rows = 0;
+        let err = loop {
+            match decoder.read_row(&mut row) {
+                Ok(()) => good_rows += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(good_rows > 0 && good_rows < 600);
+        assert!(matches!(err, QoiError::TruncatedChunk { .. }));
+        assert!(decoder.next().is_none());
+    }
+
+    /// Fails every read.
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("cable unplugged"))
+        }
+    }
+
+    #[test]
+    fn read_errors_are_not_truncation() {
+        let dice = std::fs::read("files/dice.qoi").unwrap();
+        let source = dice[..dice.len() / 2].chain(Broken);
+        let res = StreamDecoder::new(source)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>();
+        assert!(matches!(res, Err(QoiError::Io(e)) if e.to_string() == "cable unplugged"));
+    }
+
+    fn testcard() -> (Header, Vec<u8>) {
+        let header = Header {
+            width: 256,
+            height: 256,
+            channels: Channels::RGBA,
+            color_space: ColorSpace::SRGB,
+        };
+        (header, std::fs::read("files/testcard_rgba.rgba").unwrap())
+    }
+
+    #[test]
+    fn row_pushing_matches_encode_to_vec() {
+        let (header, pixels) = testcard();
+        let mut encoder = StreamEncoder::new(Vec::new(), header).unwrap();
+        // the header goes out straight away
+        assert_eq!(encoder.sink.len(), 14);
+        for row in pixels.chunks(256 * 4) {
+            encoder.write_pixels(row).unwrap();
+        }
+        let out = encoder.finish().unwrap();
+        assert!(out == encode_to_vec(&pixels, header).unwrap());
+    }
+
+    #[test]
+    fn options_set_the_mode() {
+        let pixels = [0, 0, 0, 255, 255, 255, 255, 255, 1, 1, 1, 255];
+        let options = EncodeOptions {
+            color_space: ColorSpace::SRGB,
+            mode: EncodeMode::ReferenceCompatible,
+            ..EncodeOptions::default()
+        };
+        let mut encoder = StreamEncoder::with_options(Vec::new(), 3, 1, options).unwrap();
+        let header = encoder.header();
+        assert_eq!(header.channels, Channels::RGBA);
+        encoder.write_pixels(&pixels).unwrap();
+        let out = encoder.finish().unwrap();
+        let reference = encode_to_vec_with_mode(&pixels, header, EncodeMode::ReferenceCompatible);
+        assert_eq!(out, reference.unwrap());
+        assert_ne!(out, encode_to_vec(&pixels, header).unwrap());
+    }
+
+    #[test]
+    fn io_write_accepts_split_pixels() {
+        let (header, pixels) = testcard();
+        let mut encoder = StreamEncoder::new(Vec::new(), header).unwrap();
+        let mut rest = pixels.as_slice();
+        let mut step = 1;
+        while !rest.is_empty() {
+            let n = step.min(rest.len());
+            encoder.write_all(&rest[..n]).unwrap();
+            rest = &rest[n..];
+            step = step % 13 + 1;
+        }
+        let out = encoder.finish().unwrap();
+        assert!(out == encode_to_vec(&pixels, header).unwrap());
+    }
+
+    /// A sink that refuses the next `refusals` writes.
+    struct Hiccup {
+        out: Vec<u8>,
+        refusals: usize,
+    }
+
+    impl Write for Hiccup {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if self.refusals > 0 {
+                self.refusals -= 1;
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.out.write(data)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn refused_writes_are_not_taken_twice() {
+        let header = Header {
+            width: 3,
+            height: 1,
+            channels: Channels::RGB,
+            color_space: ColorSpace::Linear,
+        };
+        let pixels = [10, 20, 30, 40, 50, 60, 70, 80, 90];
+        let sink = Hiccup {
+            out: Vec::new(),
+            refusals: 0,
+        };
+        let mut encoder = StreamEncoder::new(sink, header).unwrap();
+        encoder.sink.refusals = 2;
+        // the first pixel is taken even though its chunk cannot be sent yet
+        assert_eq!(encoder.write(&pixels[..3]).unwrap(), 3);
+        assert_eq!(encoder.sink.out.len(), 14);
+        // the waiting chunk is refused again, so the second pixel is not taken
+        let err = encoder.write(&pixels[3..6]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert_eq!(encoder.pixels_written(), 1);
+        assert_eq!(encoder.write(&pixels[3..6]).unwrap(), 3);
+        encoder.write_pixels(&pixels[6..]).unwrap();
+        let out = encoder.finish().unwrap().out;
+        assert!(out == encode_to_vec(&pixels, header).unwrap());
+    }
+
+    #[test]
+    fn wrong_pixel_counts() {
+        let header = Header {
+            width: 2,
+            height: 1,
+            channels: Channels::RGB,
+            color_space: ColorSpace::Linear,
+        };
+
+        let mut encoder = StreamEncoder::new(Vec::new(), header).unwrap();
+        encoder.write_pixels(&[1, 2, 3]).unwrap();
+        assert!(matches!(
+            encoder.write_pixels(&[1, 2]),
+            Err(QoiError::UnalignedInput {
+                len: 2,
+                channels: 3
+            })
+        ));
+        assert!(matches!(
+            encoder.write_pixels(&[1, 2, 3, 4, 5, 6]),
+            Err(QoiError::BadInputLength {
+                expected: 6,
+                actual: 9
+            })
+        ));
+        assert!(matches!(
+            encoder.finish(),
+            Err(QoiError::BadInputLength {
+                expected: 6,
+                actual: 3
+            })
+        ));
+
+        let mut encoder = StreamEncoder::new(Vec::new(), header).unwrap();
+        encoder.write_all(&[1, 2]).unwrap();
+        assert!(matches!(
+            encoder.write_pixels(&[1, 2, 3]),
+            Err(QoiError::UnalignedInput {
+                len: 5,
+                channels: 3
+            })
+        ));
+
+        let mut encoder = StreamEncoder::new(Vec::new(), header).unwrap();
+        let err = encoder.write(&[0; 7]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,480 @@
+//! Truevision TGA import and export, to and from the row-major pixel
+//! matrices that [`QOIImage::to_rgba_mat`](crate::QOIImage::to_rgba_mat) and
+//! [`QOIImage::from_rgba_mat`](crate::QOIImage::from_rgba_mat) use.
+//!
+//! Truecolor (15, 16, 24 or 32-bit), greyscale (8-bit, or 16-bit with alpha)
+//! and 8-bit color-mapped images are read, raw or run-length encoded, in any
+//! of the four orientations. Files are written bottom-up, as 24-bit when
+//! every pixel is opaque and as 32-bit otherwise.
+
+use std::fmt;
+
+use crate::{Channels, ColorSpace, Header, LimitKind, Limits, PixelRGBA, QoiError};
+
+const HEADER_LEN: usize = 18;
+const COLOR_MAPPED: u8 = 1;
+const TRUECOLOR: u8 = 2;
+const GREYSCALE: u8 = 3;
+// adding this to an image type makes it the run-length encoded variant
+const RLE: u8 = 8;
+// image descriptor bits: pixels stored right to left, rows top to bottom
+const RIGHT_TO_LEFT: u8 = 0x10;
+const TOP_TO_BOTTOM: u8 = 0x20;
+// longest run or raw packet
+const MAX_PACKET: usize = 128;
+// marks a file as TGA 2.0; the extension and developer areas are left out
+const FOOTER_SIGNATURE: &[u8; 18] = b"TRUEVISION-XFILE.\0";
+
+/// Whether to run-length encode pixels when writing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Rle,
+}
+
+/// Everything that can go wrong while reading a TGA file.
+#[derive(Debug)]
+pub enum TgaError {
+    /// A header field is not a valid value.
+    BadHeader(&'static str),
+    /// The image type or pixel depth is not one this module reads.
+    Unsupported { image_type: u8, pixel_depth: u8 },
+    /// The file ended before the last pixel.
+    TruncatedData,
+    /// The run-length packet at `offset` goes past the end of the image.
+    RunOverflow { offset: usize },
+    /// A color-mapped pixel refers past the end of the color map.
+    BadColorIndex { index: usize },
+    /// The image is too large for the limits, or too large to store as TGA.
+    Image(QoiError),
+}
+
+impl fmt::Display for TgaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TgaError::BadHeader(msg) => write!(f, "Malformed TGA: {msg}"),
+            TgaError::Unsupported {
+                image_type,
+                pixel_depth,
+            } => write!(
+                f,
+                "Unsupported TGA: image type {image_type} at {pixel_depth} bits per pixel"
+            ),
+            TgaError::TruncatedData => write!(f, "Malformed TGA: pixel data ends early"),
+            TgaError::RunOverflow { offset } => write!(
+                f,
+                "Malformed TGA: packet at byte {offset} goes past the end of the image"
+            ),
+            TgaError::BadColorIndex { index } => write!(
+                f,
+                "Malformed TGA: color index {index} is outside the color map"
+            ),
+            TgaError::Image(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TgaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TgaError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<QoiError> for TgaError {
+    fn from(e: QoiError) -> Self {
+        TgaError::Image(e)
+    }
+}
+
+/// Decodes a TGA into rows of pixels, top row first, with the default
+/// [`Limits`].
+pub fn decode(data: &[u8]) -> Result<Vec<Vec<PixelRGBA>>, TgaError> {
+    decode_with_limits(data, Limits::default())
+}
+
+/// Like [`decode`], but rejects images the `limits` do not allow before
+/// reading any pixels.
+pub fn decode_with_limits(data: &[u8], limits: Limits) -> Result<Vec<Vec<PixelRGBA>>, TgaError> {
+    let header = data
+        .get(..HEADER_LEN)
+        .ok_or(TgaError::BadHeader("file is too short"))?;
+    let u16_at = |pos: usize| u16::from_le_bytes([header[pos], header[pos + 1]]);
+    let id_len = header[0] as usize;
+    let has_color_map = header[1];
+    let image_type = header[2];
+    let (map_first, map_len, map_depth) = (u16_at(3) as usize, u16_at(5) as usize, header[7]);
+    let (width, height) = (u16_at(12) as usize, u16_at(14) as usize);
+    let pixel_depth = header[16];
+    let descriptor = header[17];
+    let alpha_bits = descriptor & 0x0f;
+
+    let unsupported = TgaError::Unsupported {
+        image_type,
+        pixel_depth,
+    };
+    let supported = match image_type & !RLE {
+        COLOR_MAPPED => pixel_depth == 8 && has_color_map == 1,
+        TRUECOLOR => matches!(pixel_depth, 15 | 16 | 24 | 32),
+        GREYSCALE => matches!(pixel_depth, 8 | 16),
+        _ => false,
+    };
+    if !supported {
+        return Err(unsupported);
+    }
+    if has_color_map > 1 {
+        return Err(TgaError::BadHeader("invalid color map type"));
+    }
+
+    limits.check(&Header {
+        width: width as u32,
+        height: height as u32,
+        channels: Channels::RGBA,
+        color_space: ColorSpace::SRGB,
+    })?;
+
+    // the color map follows the image id, and may be present even when the
+    // pixels do not use it
+    let map_start = HEADER_LEN + id_len;
+    let map_entry_len = (map_depth as usize).div_ceil(8);
+    let map_bytes = if has_color_map == 1 {
+        map_len * map_entry_len
+    } else {
+        0
+    };
+    let map_data = data
+        .get(map_start..map_start + map_bytes)
+        .ok_or(TgaError::TruncatedData)?;
+    let color_map = if image_type & !RLE == COLOR_MAPPED {
+        if !matches!(map_depth, 15 | 16 | 24 | 32) {
+            return Err(TgaError::BadHeader("invalid color map entry size"));
+        }
+        map_data
+            .chunks_exact(map_entry_len)
+            .map(|entry| truecolor(entry, alpha_bits))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let pixel_len = (pixel_depth as usize).div_ceil(8);
+    let to_rgba = |px: &[u8]| -> Result<PixelRGBA, TgaError> {
+        match image_type & !RLE {
+            COLOR_MAPPED => {
+                let index = (px[0] as usize).wrapping_sub(map_first);
+                color_map
+                    .get(index)
+                    .copied()
+                    .ok_or(TgaError::BadColorIndex {
+                        index: px[0] as usize,
+                    })
+            }
+            TRUECOLOR => Ok(truecolor(px, alpha_bits)),
+            _ => {
+                let alpha = if pixel_len == 2 { px[1] } else { 255 };
+                Ok(PixelRGBA::new(px[0], px[0], px[0], alpha))
+            }
+        }
+    };
+
+    let count = width * height;
+    let mut pixels = Vec::with_capacity(count.min(data.len()));
+    let mut pos = map_start + map_bytes;
+    let read = |pos: usize, n: usize| {
+        data.get(pos..pos + n * pixel_len)
+            .ok_or(TgaError::TruncatedData)
+    };
+    if image_type & RLE == 0 {
+        for px in read(pos, count)?.chunks_exact(pixel_len) {
+            pixels.push(to_rgba(px)?);
+        }
+    } else {
+        while pixels.len() < count {
+            let offset = pos;
+            let packet = *data.get(pos).ok_or(TgaError::TruncatedData)?;
+            let n = (packet & 0x7f) as usize + 1;
+            if pixels.len() + n > count {
+                return Err(TgaError::RunOverflow { offset });
+            }
+            if packet & 0x80 != 0 {
+                let px = to_rgba(read(pos + 1, 1)?)?;
+                pixels.extend(std::iter::repeat_n(px, n));
+                pos += 1 + pixel_len;
+            } else {
+                for px in read(pos + 1, n)?.chunks_exact(pixel_len) {
+                    pixels.push(to_rgba(px)?);
+                }
+                pos += 1 + n * pixel_len;
+            }
+        }
+    }
+
+    // 32-bit files that claim no alpha bits, and some that claim them,
+    // leave every alpha byte at zero; those are opaque images
+    if pixel_depth == 32 && pixels.iter().all(|px| px.a == 0) {
+        pixels.iter_mut().for_each(|px| px.a = 255);
+    }
+
+    // as with to_rgba_mat, a zero width image has no rows
+    let mut rows: Vec<Vec<PixelRGBA>> = pixels
+        .chunks_exact(width.max(1))
+        .map(<[_]>::to_vec)
+        .collect();
+    if descriptor & TOP_TO_BOTTOM == 0 {
+        rows.reverse();
+    }
+    if descriptor & RIGHT_TO_LEFT != 0 {
+        rows.iter_mut().for_each(|row| row.reverse());
+    }
+    Ok(rows)
+}
+
+/// Encodes rows of pixels, top row first, as a TGA.
+///
+/// # Panics
+///
+/// If the rows are not all the same length.
+pub fn encode(src: &[Vec<PixelRGBA>], compression: Compression) -> Result<Vec<u8>, TgaError> {
+    let width = src.first().map_or(0, Vec::len);
+    assert!(
+        src.iter().all(|row| row.len() == width),
+        "rows are not all the same length"
+    );
+    let too_big = |kind, value: usize| QoiError::LimitExceeded {
+        kind,
+        value: value as u64,
+        max: u16::MAX as u64,
+    };
+    if width > u16::MAX as usize {
+        return Err(too_big(LimitKind::Width, width).into());
+    }
+    if src.len() > u16::MAX as usize {
+        return Err(too_big(LimitKind::Height, src.len()).into());
+    }
+
+    let alpha = src.iter().flatten().any(|px| px.a != 255);
+    let pixel_len = if alpha { 4 } else { 3 };
+    let image_type = match compression {
+        Compression::None => TRUECOLOR,
+        Compression::Rle => TRUECOLOR + RLE,
+    };
+
+    let mut res = Vec::with_capacity(HEADER_LEN + width * src.len() * pixel_len);
+    // no image id and no color map
+    res.extend_from_slice(&[0, 0, image_type, 0, 0, 0, 0, 0]);
+    // x and y origin
+    res.extend_from_slice(&[0; 4]);
+    res.extend_from_slice(&(width as u16).to_le_bytes());
+    res.extend_from_slice(&(src.len() as u16).to_le_bytes());
+    // bottom-up, with eight alpha bits if there is alpha
+    res.extend_from_slice(&[pixel_len as u8 * 8, if alpha { 8 } else { 0 }]);
+
+    let put = |res: &mut Vec<u8>, px: &PixelRGBA| {
+        res.extend_from_slice(&[px.b, px.g, px.r, px.a][..pixel_len]);
+    };
+    for row in src.iter().rev() {
+        match compression {
+            Compression::None => row.iter().for_each(|px| put(&mut res, px)),
+            // packets are kept within a row, as TGA 2.0 asks
+            Compression::Rle => {
+                let mut i = 0;
+                while i < row.len() {
+                    let run = row[i..]
+                        .iter()
+                        .take(MAX_PACKET)
+                        .take_while(|&&px| px == row[i])
+                        .count();
+                    if run > 1 {
+                        res.push(0x80 | (run - 1) as u8);
+                        put(&mut res, &row[i]);
+                        i += run;
+                        continue;
+                    }
+                    // a raw packet lasts until the next run of two or more
+                    let mut n = 1;
+                    while i + n < row.len()
+                        && n < MAX_PACKET
+                        && row.get(i + n + 1) != Some(&row[i + n])
+                    {
+                        n += 1;
+                    }
+                    res.push((n - 1) as u8);
+                    row[i..i + n].iter().for_each(|px| put(&mut res, px));
+                    i += n;
+                }
+            }
+        }
+    }
+
+    // no extension or developer area
+    res.extend_from_slice(&[0; 8]);
+    res.extend_from_slice(FOOTER_SIGNATURE);
+    Ok(res)
+}
+
+/// Reads a little-endian truecolor pixel or color map entry: BGR or BGRA
+/// bytes, or 5 bits per channel with one attribute bit.
+fn truecolor(px: &[u8], alpha_bits: u8) -> PixelRGBA {
+    match *px {
+        [b, g, r, a] => PixelRGBA::new(r, g, b, a),
+        [b, g, r] => PixelRGBA::new(r, g, b, 255),
+        [lo, hi, ..] => {
+            let value = u16::from_le_bytes([lo, hi]);
+            let channel = |shift: u16| {
+                let v = (value >> shift & 0x1f) as u8;
+                v << 3 | v >> 2
+            };
+            // the top bit is alpha only when the descriptor says so
+            let a = if alpha_bits == 0 || value & 0x8000 != 0 {
+                255
+            } else {
+                0
+            };
+            PixelRGBA::new(channel(10), channel(5), channel(0), a)
+        }
+        _ => PixelRGBA::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice_mat;
+
+    /// A TGA with no image id or color map.
+    fn tga(image_type: u8, width: u16, height: u16, depth: u8, descriptor: u8) -> Vec<u8> {
+        let mut res = vec![0, 0, image_type, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        res.extend_from_slice(&width.to_le_bytes());
+        res.extend_from_slice(&height.to_le_bytes());
+        res.extend_from_slice(&[depth, descriptor]);
+        res
+    }
+
+    #[test]
+    fn round_trips() {
+        let opaque: Vec<Vec<PixelRGBA>> = dice_mat()
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|px| PixelRGBA { a: 255, ..px })
+                    .collect()
+            })
+            .collect();
+        for src in [dice_mat(), opaque] {
+            for compression in [Compression::None, Compression::Rle] {
+                let encoded = encode(&src, compression).unwrap();
+                assert_eq!(decode(&encoded).unwrap(), src);
+            }
+        }
+        let rle = encode(&dice_mat(), Compression::Rle).unwrap();
+        assert!(rle.len() < encode(&dice_mat(), Compression::None).unwrap().len());
+
+        // packets of every kind and length
+        let row: Vec<PixelRGBA> = (0..300u32)
+            .map(|i| PixelRGBA::from(if i % 7 < 3 || i > 150 { 0 } else { i }))
+            .collect();
+        let src = vec![row.clone(), row];
+        assert_eq!(
+            decode(&encode(&src, Compression::Rle).unwrap()).unwrap(),
+            src
+        );
+    }
+
+    #[test]
+    fn orientation() {
+        let [a, b, c, d] = [[1, 2, 3], [4, 5, 6], [7, 8, 9], [10, 11, 12]];
+        let px = |[b, g, r]: [u8; 3]| PixelRGBA::new(r, g, b, 255);
+        for (descriptor, expected) in [
+            (0, [[c, d], [a, b]]),
+            (TOP_TO_BOTTOM, [[a, b], [c, d]]),
+            (RIGHT_TO_LEFT, [[d, c], [b, a]]),
+            (TOP_TO_BOTTOM | RIGHT_TO_LEFT, [[b, a], [d, c]]),
+        ] {
+            let mut data = tga(TRUECOLOR, 2, 2, 24, descriptor);
+            data.extend_from_slice(&[a, b, c, d].concat());
+            let expected: Vec<Vec<PixelRGBA>> = expected
+                .iter()
+                .map(|row| row.iter().map(|&p| px(p)).collect())
+                .collect();
+            assert_eq!(decode(&data).unwrap(), expected);
+        }
+
+        // no pixels, however many rows the header claims
+        let data = tga(TRUECOLOR, 0, 600, 24, 0);
+        assert!(decode(&data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn other_pixel_types() {
+        // RLE greyscale, a run of three then a raw packet of one
+        let mut data = tga(GREYSCALE + RLE, 4, 1, 8, TOP_TO_BOTTOM);
+        data.extend_from_slice(&[0x82, 50, 0x00, 200]);
+        let grey = |v| PixelRGBA::new(v, v, v, 255);
+        assert_eq!(
+            decode(&data).unwrap(),
+            [[grey(50), grey(50), grey(50), grey(200)]]
+        );
+
+        // 16-bit truecolor with an attribute bit for alpha
+        let mut data = tga(TRUECOLOR, 2, 1, 16, TOP_TO_BOTTOM | 1);
+        data.extend_from_slice(&0xfc00u16.to_le_bytes());
+        data.extend_from_slice(&0x001fu16.to_le_bytes());
+        assert_eq!(
+            decode(&data).unwrap(),
+            [[PixelRGBA::new(255, 0, 0, 255), PixelRGBA::new(0, 0, 255, 0)]]
+        );
+
+        // color-mapped, with a 24-bit map starting at index 1
+        let mut data = tga(COLOR_MAPPED, 2, 1, 8, TOP_TO_BOTTOM);
+        data[1] = 1;
+        data[3..8].copy_from_slice(&[1, 0, 2, 0, 24]);
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+        data.extend_from_slice(&[2, 1]);
+        assert_eq!(
+            decode(&data).unwrap(),
+            [[PixelRGBA::new(6, 5, 4, 255), PixelRGBA::new(3, 2, 1, 255)]]
+        );
+        *data.last_mut().unwrap() = 3;
+        assert!(matches!(
+            decode(&data),
+            Err(TgaError::BadColorIndex { index: 3 })
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(decode(&[0; 10]), Err(TgaError::BadHeader(_))));
+        assert!(matches!(
+            decode(&tga(TRUECOLOR, 1, 1, 8, 0)),
+            Err(TgaError::Unsupported {
+                image_type: 2,
+                pixel_depth: 8
+            })
+        ));
+        assert!(matches!(
+            decode(&tga(TRUECOLOR, 2, 1, 24, 0)),
+            Err(TgaError::TruncatedData)
+        ));
+        let mut data = tga(TRUECOLOR + RLE, 2, 1, 24, 0);
+        data.extend_from_slice(&[0x82, 1, 2, 3]);
+        assert!(matches!(
+            decode(&data),
+            Err(TgaError::RunOverflow { offset: 18 })
+        ));
+        let tiny = Limits {
+            max_width: 1,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode_with_limits(&tga(TRUECOLOR, 2, 1, 24, 0), tiny),
+            Err(TgaError::Image(QoiError::LimitExceeded { .. }))
+        ));
+        let wide = vec![vec![PixelRGBA::default(); 65536]];
+        assert!(matches!(
+            encode(&wide, Compression::None),
+            Err(TgaError::Image(QoiError::LimitExceeded { .. }))
+        ));
+    }
+}